@@ -0,0 +1,115 @@
+//! Benchmarks for censoring player names in large captures
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use net_replay_test::options::ServerOptions;
+use net_replay_test::packet::{Packet, PacketDirection, PacketProtocol};
+use net_replay_test::packet_filter::{packet_name_replace, raw_replace, Replacement, Replacer};
+use net_replay_test::value::CommonValue;
+use net_replay_test::{QueryOptions, QueryReplay, REPLAY_VERSION};
+
+/// A2S_PLAYER counts players in a single byte
+const PLAYER_COUNTS: [usize; 3] = [16, 128, 255];
+
+fn player_names(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| format!("Player_{:04}_name", i))
+        .collect()
+}
+
+/// A source A2S_PLAYER response listing every player
+fn player_response(names: &[String]) -> Vec<u8> {
+    let count = u8::try_from(names.len()).expect("At most 255 players");
+    let mut data = vec![0xff, 0xff, 0xff, 0xff, b'D', count];
+    for (i, name) in (0..=count).zip(names) {
+        data.push(i);
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(&u32::from(i).to_le_bytes());
+        data.extend_from_slice(&1.5f32.to_le_bytes());
+    }
+    data
+}
+
+fn replay(names: &[String]) -> QueryReplay {
+    let data = player_response(names);
+    QueryReplay {
+        query: QueryOptions {
+            game: "csgo".to_string(),
+            address: "192.0.2.1".to_string(),
+            port: Some(27015),
+            request: Default::default(),
+        },
+        server: ServerOptions {
+            tcp_port: None,
+            udp_port: Some(27015),
+            packet_size: data.len(),
+        },
+        packets: vec![Packet {
+            direction: PacketDirection::FromServer,
+            protocol: PacketProtocol::Udp,
+            src_port: 27015,
+            dst_port: 4000,
+            data,
+        }],
+        value: CommonValue {
+            name: None,
+            map: None,
+            has_password: None,
+            players_online: Some(names.len() as u64),
+            players_maximum: None,
+            player_names: names.iter().cloned().collect(),
+        },
+        replay_version: REPLAY_VERSION,
+    }
+}
+
+fn replace(c: &mut Criterion) {
+    let mut group = c.benchmark_group("replace");
+
+    for count in PLAYER_COUNTS {
+        let names = player_names(count);
+        let data = player_response(&names);
+        let replacements: Vec<Replacement> = names
+            .iter()
+            .map(|name| (name.as_bytes().to_vec(), vec![b'x'; name.len()]))
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("per_name", count), &data, |b, data| {
+            b.iter(|| {
+                let mut data = data.clone();
+                for (name, replacement) in &replacements {
+                    raw_replace(&mut data, name, replacement).unwrap();
+                }
+                data
+            })
+        });
+
+        let replacer = Replacer::new(&replacements).unwrap();
+        group.bench_with_input(BenchmarkId::new("single_pass", count), &data, |b, data| {
+            b.iter(|| {
+                let mut data = data.clone();
+                replacer.replace_in_place(&mut data).unwrap();
+                data
+            })
+        });
+
+        let replay = replay(&names);
+        group.bench_with_input(
+            BenchmarkId::new("packet_name_replace", count),
+            &replay,
+            |b, replay| {
+                b.iter(|| {
+                    let mut replay = replay.clone();
+                    packet_name_replace(&mut replay, 0).unwrap();
+                    replay
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, replace);
+criterion_main!(benches);
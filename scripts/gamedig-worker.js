@@ -0,0 +1,45 @@
+#!/usr/bin/env node
+// Long-lived node-gamedig worker used by NodeImpl.
+//
+// Usage: node gamedig-worker.js <path to node-gamedig package>
+//
+// Reads one JSON query per line on stdin: {"id": 1, "type": "csgo", "host": "127.0.0.1", "port": 27015}
+// Writes one JSON response per line on stdout: {"id": 1, "result": {...}} or {"id": 1, "error": "..."}
+
+const path = require('path');
+const readline = require('readline');
+
+const gamedigPath = path.resolve(process.argv[2] || './node-gamedig');
+const gamedig = require(gamedigPath);
+// node-gamedig 5 exports a GameDig class, older versions export the query function directly
+const query = gamedig.GameDig
+  ? (options) => gamedig.GameDig.query(options)
+  : (options) => gamedig.query(options);
+
+const respond = (response) => process.stdout.write(JSON.stringify(response) + '\n');
+
+const input = readline.createInterface({ input: process.stdin, terminal: false });
+
+input.on('line', async (line) => {
+  if (!line.trim()) return;
+
+  let request;
+  try {
+    request = JSON.parse(line);
+  } catch (e) {
+    respond({ id: null, error: 'Invalid request: ' + e.message });
+    return;
+  }
+
+  const { id, ...options } = request;
+  if (options.port === null || options.port === undefined) delete options.port;
+
+  try {
+    const result = await query(options);
+    respond({ id, result });
+  } catch (e) {
+    respond({ id, error: e && e.message ? e.message : String(e) });
+  }
+});
+
+input.on('close', () => process.exit(0));
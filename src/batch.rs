@@ -0,0 +1,197 @@
+//! Running many replays, each in isolation, and summarising the results
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::implementations::QueryImplementation;
+use crate::options::RequestSettings;
+use crate::{run_replay, Error, QueryReplay, ReplayOutcome};
+
+/// Overall result of a single replay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum ReplayStatus {
+    /// The implementation produced the stored value
+    Pass,
+    /// The implementation produced a different value, sent different packets or didn't send every
+    /// packet
+    Mismatch,
+    /// The replay or implementation failed
+    Error,
+    /// The replay file is for a different replay version
+    WrongVersion,
+}
+
+impl ReplayStatus {
+    /// Process exit code for this status, for a batch the highest code should be used
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ReplayStatus::Pass => 0,
+            ReplayStatus::Mismatch => 1,
+            ReplayStatus::Error => 2,
+            ReplayStatus::WrongVersion => 3,
+        }
+    }
+}
+
+impl std::fmt::Display for ReplayStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ReplayStatus::Pass => "pass",
+            ReplayStatus::Mismatch => "mismatch",
+            ReplayStatus::Error => "error",
+            ReplayStatus::WrongVersion => "version",
+        })
+    }
+}
+
+/// The result of replaying a single file in a batch
+#[derive(Debug)]
+pub struct ReplayRun {
+    pub path: PathBuf,
+    pub status: ReplayStatus,
+    /// Set if the replay ran to completion
+    pub outcome: Option<ReplayOutcome>,
+    /// Set if the replay failed
+    pub error: Option<String>,
+    /// Total time taken including loading the file and starting the implementation
+    pub duration: Duration,
+}
+
+/// Replay every file, continuing after failures. Replays are run on `jobs` threads, each with
+/// their own implementation from `create_implementation`, results are returned in the same order
+/// as the paths.
+pub fn run_batch(
+    paths: &[PathBuf],
+    create_implementation: &(dyn Fn() -> Result<Box<dyn QueryImplementation>, Error> + Sync),
+    jobs: usize,
+    overrides: &RequestSettings,
+) -> Vec<ReplayRun> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..paths.len()).map(|_| None).collect::<Vec<_>>());
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, paths.len().max(1)) {
+            scope.spawn(|| {
+                let mut implementation = None;
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = paths.get(index) else {
+                        break;
+                    };
+
+                    let run = run_one(path, &mut implementation, create_implementation, overrides);
+                    results.lock().unwrap()[index] = Some(run);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|run| run.expect("Every replay should have run"))
+        .collect()
+}
+
+fn run_one(
+    path: &PathBuf,
+    implementation: &mut Option<Box<dyn QueryImplementation>>,
+    create_implementation: &(dyn Fn() -> Result<Box<dyn QueryImplementation>, Error> + Sync),
+    overrides: &RequestSettings,
+) -> ReplayRun {
+    let start_time = Instant::now();
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let mut query_replay = QueryReplay::load(path)?;
+        query_replay.query.request.merge(overrides);
+
+        let implementation = match implementation {
+            Some(implementation) => implementation,
+            None => implementation.insert(create_implementation()?),
+        };
+
+        run_replay(implementation.as_ref(), query_replay)
+    }))
+    .unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Unknown panic".to_string());
+        // The implementation may be in a bad state after a panic
+        *implementation = None;
+        Err(Error::String(format!("Panicked: {}", message)))
+    });
+
+    let duration = start_time.elapsed();
+
+    match result {
+        Ok(outcome) => ReplayRun {
+            path: path.clone(),
+            status: if outcome.matches() && outcome.packets_match() {
+                ReplayStatus::Pass
+            } else {
+                ReplayStatus::Mismatch
+            },
+            outcome: Some(outcome),
+            error: None,
+            duration,
+        },
+        Err(error) => ReplayRun {
+            path: path.clone(),
+            status: if matches!(error, Error::WrongReplayVersion { .. }) {
+                ReplayStatus::WrongVersion
+            } else {
+                ReplayStatus::Error
+            },
+            outcome: None,
+            error: Some(format!("{:?}", error)),
+            duration,
+        },
+    }
+}
+
+/// Print a table of each replay's status and duration followed by totals
+pub fn print_summary(runs: &[ReplayRun]) {
+    let width = runs
+        .iter()
+        .map(|run| run.path.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("replay".len());
+
+    println!("{:<width$}  {:<8}  time", "replay", "status");
+    for run in runs {
+        println!(
+            "{:<width$}  {:<8}  {:?}",
+            run.path.display().to_string(),
+            run.status,
+            run.duration
+        );
+    }
+
+    let count = |status| runs.iter().filter(|run| run.status == status).count();
+    let total_duration: Duration = runs.iter().map(|run| run.duration).sum();
+    println!(
+        "\n{} passed, {} mismatched, {} errored, {} wrong version ({} total in {:?})",
+        count(ReplayStatus::Pass),
+        count(ReplayStatus::Mismatch),
+        count(ReplayStatus::Error),
+        count(ReplayStatus::WrongVersion),
+        runs.len(),
+        total_duration
+    );
+}
+
+/// Exit code for a batch, the most severe status wins
+pub fn exit_code(runs: &[ReplayRun]) -> i32 {
+    runs.iter()
+        .map(|run| run.status.exit_code())
+        .max()
+        .unwrap_or(0)
+}
@@ -0,0 +1,364 @@
+//! Capturing replays for a list of servers
+//!
+//! Server lists are either CSV with a `game,address,port` line per server (port is optional and
+//! lines starting with `#` are ignored) or, with the toml feature, TOML with a `[[server]]` table
+//! per server using the same fields as [QueryOptions].
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::implementations::QueryImplementation;
+use crate::options::RequestSettings;
+use crate::packet_filter::{CaptureRedaction, FilterError};
+use crate::{run_capture, Error, QueryOptions};
+
+/// Settings for capturing a list of servers
+#[derive(Debug, Clone)]
+pub struct CaptureListSettings {
+    /// Directory to write replays and the manifest to
+    pub output_dir: PathBuf,
+    /// Minimum time between starting each capture
+    pub delay: Duration,
+    /// Number of times to retry a failed capture
+    pub retries: usize,
+    /// Overrides for each server's request settings, e.g. the query timeout
+    pub request: RequestSettings,
+    /// Network device to capture on
+    pub device: Option<String>,
+    /// Censor player names with pseudonyms of the same length
+    pub censor_player_names: bool,
+    /// Seed for the pseudonyms, see [crate::packet_filter::DEFAULT_PSEUDONYM_SEED]
+    pub pseudonym_seed: u64,
+    /// Save replays even if censored or redacted strings are still found in them
+    pub allow_leaks: bool,
+    /// Redaction applied to each replay, a redacted address is also used in file names and the
+    /// manifest
+    pub redaction: CaptureRedaction,
+}
+
+impl Default for CaptureListSettings {
+    fn default() -> Self {
+        Self {
+            output_dir: PathBuf::from("."),
+            delay: Duration::from_secs(1),
+            retries: 1,
+            request: RequestSettings::default(),
+            device: None,
+            censor_player_names: false,
+            pseudonym_seed: crate::packet_filter::DEFAULT_PSEUDONYM_SEED,
+            allow_leaks: false,
+            redaction: CaptureRedaction::default(),
+        }
+    }
+}
+
+/// The result of capturing one server in the list
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct CaptureListEntry {
+    pub game: String,
+    pub address: String,
+    pub port: Option<u16>,
+    /// Replay file written if the capture succeeded
+    pub file: Option<PathBuf>,
+    /// Error from the last attempt if every attempt failed
+    pub error: Option<String>,
+    pub attempts: usize,
+}
+
+/// Manifest written alongside the replays of a list capture
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct CaptureManifest {
+    pub entries: Vec<CaptureListEntry>,
+}
+
+impl CaptureManifest {
+    pub fn succeeded(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.file.is_some())
+            .count()
+    }
+}
+
+/// Name of the manifest file in the output directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Load a server list, files ending in `.toml` are parsed as TOML, anything else as CSV
+pub fn load_server_list(path: impl AsRef<Path>) -> Result<Vec<QueryOptions>, Error> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)?;
+
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        parse_toml(&content)
+    } else {
+        parse_csv(&content)
+    }
+}
+
+/// Parse a CSV server list, a header line starting with "game" is skipped
+pub fn parse_csv(content: &str) -> Result<Vec<QueryOptions>, Error> {
+    let mut servers = Vec::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if line_number == 0 && fields[0].eq_ignore_ascii_case("game") {
+            continue;
+        }
+
+        let invalid = |reason: &str| {
+            Error::String(format!(
+                "Invalid server list line {}: {} ({:?})",
+                line_number + 1,
+                reason,
+                line
+            ))
+        };
+
+        let (game, address, port) = match fields[..] {
+            [game, address] => (game, address, ""),
+            [game, address, port] => (game, address, port),
+            _ => return Err(invalid("expected game,address[,port]")),
+        };
+        if game.is_empty() || address.is_empty() {
+            return Err(invalid("game and address are required"));
+        }
+        let port = if port.is_empty() {
+            None
+        } else {
+            Some(port.parse().map_err(|_| invalid("invalid port"))?)
+        };
+
+        servers.push(QueryOptions {
+            game: game.to_string(),
+            address: address.to_string(),
+            port,
+            request: RequestSettings::default(),
+        });
+    }
+    Ok(servers)
+}
+
+#[cfg(feature = "toml")]
+pub fn parse_toml(content: &str) -> Result<Vec<QueryOptions>, Error> {
+    #[derive(serde::Deserialize)]
+    struct ServerList {
+        #[serde(default)]
+        server: Vec<QueryOptions>,
+    }
+
+    let list: ServerList = toml::from_str(content)
+        .map_err(|e| Error::String(format!("Invalid server list: {}", e)))?;
+    Ok(list.server)
+}
+
+#[cfg(not(feature = "toml"))]
+pub fn parse_toml(_content: &str) -> Result<Vec<QueryOptions>, Error> {
+    Err(Error::String(
+        "TOML server lists require the toml feature".to_string(),
+    ))
+}
+
+/// File name for a server's replay in a list capture, a number is added if the name was already
+/// used, e.g. by several servers whose addresses were redacted
+fn replay_file_name(options: &QueryOptions, address: &str, used: &mut HashSet<String>) -> String {
+    let mut name = format!("{}-{}", options.game, address);
+    if let Some(port) = options.port {
+        name.push_str(&format!("-{}", port));
+    }
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let mut file_name = format!("replay-{}.json", name);
+    let mut n = 1;
+    while !used.insert(file_name.clone()) {
+        n += 1;
+        file_name = format!("replay-{}-{}.json", name, n);
+    }
+    file_name
+}
+
+/// Capture each server in turn, retrying failures, and write a replay per server and a manifest
+/// to the output directory. Replays that already exist are overwritten.
+pub fn capture_list(
+    implementation: &dyn QueryImplementation,
+    servers: Vec<QueryOptions>,
+    settings: &CaptureListSettings,
+) -> Result<CaptureManifest, Error> {
+    std::fs::create_dir_all(&settings.output_dir)?;
+
+    let mut entries = Vec::with_capacity(servers.len());
+    let mut last_start: Option<Instant> = None;
+    let mut used_names = HashSet::new();
+
+    for mut options in servers {
+        options.request.merge(&settings.request);
+
+        let address = settings.redaction.address(&options.address);
+        let file = settings
+            .output_dir
+            .join(replay_file_name(&options, &address, &mut used_names));
+
+        let mut entry = CaptureListEntry {
+            game: options.game.clone(),
+            address,
+            port: options.port,
+            file: None,
+            error: None,
+            attempts: 0,
+        };
+
+        while entry.attempts <= settings.retries {
+            // Rate limit every query, including retries
+            if let Some(last_start) = last_start {
+                let elapsed = last_start.elapsed();
+                if elapsed < settings.delay {
+                    std::thread::sleep(settings.delay - elapsed);
+                }
+            }
+            last_start = Some(Instant::now());
+            entry.attempts += 1;
+
+            println!(
+                "Capturing {} {} (attempt {})",
+                options.game, entry.address, entry.attempts
+            );
+
+            let result = run_capture(
+                implementation,
+                options.clone(),
+                settings.device.as_deref(),
+                None::<&Path>,
+                settings.censor_player_names,
+                settings.pseudonym_seed,
+                settings.allow_leaks,
+            )
+            .and_then(|mut replay| {
+                settings
+                    .redaction
+                    .apply(&mut replay, settings.allow_leaks)?;
+                replay.save(&file)
+            });
+
+            match result {
+                Ok(()) => {
+                    entry.file = Some(file.clone());
+                    entry.error = None;
+                    break;
+                }
+                // The leaks are the strings that were meant to be redacted, keep them out of the
+                // manifest
+                Err(Error::Filter(FilterError::Leaked(leaks))) => {
+                    println!("Capture failed, redacted strings were still found");
+                    entry.error = Some(format!("{} redacted string(s) still found", leaks.len()));
+                }
+                Err(e) => {
+                    println!("Capture failed: {:?}", e);
+                    entry.error = Some(format!("{:?}", e));
+                }
+            }
+        }
+
+        entries.push(entry);
+    }
+
+    let manifest = CaptureManifest { entries };
+    let file = std::fs::File::create(settings.output_dir.join(MANIFEST_FILE))?;
+    let mut writer = std::io::BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &manifest)?;
+    std::io::Write::flush(&mut writer)?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::{parse_csv, replay_file_name};
+    use crate::packet_filter::CaptureRedaction;
+
+    #[test]
+    fn csv_server_list() {
+        let servers = parse_csv(
+            "game,address,port\n\
+             csgo, 127.0.0.1, 27015\n\
+             # comment\n\
+             \n\
+             minecraft,mc.example.com\n",
+        )
+        .unwrap();
+
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].game, "csgo");
+        assert_eq!(servers[0].address, "127.0.0.1");
+        assert_eq!(servers[0].port, Some(27015));
+        assert_eq!(servers[1].address, "mc.example.com");
+        assert_eq!(servers[1].port, None);
+
+        assert!(parse_csv("csgo,127.0.0.1,notaport").is_err());
+        assert!(parse_csv("csgo").is_err());
+    }
+
+    #[test]
+    fn redacted_file_names() {
+        let servers = parse_csv(
+            "csgo,198.51.100.7,27015
+csgo,203.0.113.9,27015
+",
+        )
+        .unwrap();
+        let redaction = CaptureRedaction {
+            redact: true,
+            ..Default::default()
+        };
+
+        let mut used = HashSet::new();
+        let names: Vec<String> = servers
+            .iter()
+            .map(|options| {
+                replay_file_name(options, &redaction.address(&options.address), &mut used)
+            })
+            .collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "replay-csgo-192.0.2.1-27015.json",
+                "replay-csgo-192.0.2.1-27015-2.json",
+            ]
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_server_list() {
+        let servers = super::parse_toml(
+            "[[server]]\n\
+             game = \"csgo\"\n\
+             address = \"127.0.0.1\"\n\
+             port = 27015\n\
+             request = { gather_players = false }\n",
+        )
+        .unwrap();
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].port, Some(27015));
+        assert_eq!(servers[0].request.gather_players, Some(false));
+    }
+}
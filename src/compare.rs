@@ -0,0 +1,289 @@
+//! Differential testing of multiple implementations against the same replays
+
+use std::path::{Path, PathBuf};
+
+use crate::implementations::QueryImplementation;
+use crate::options::RequestSettings;
+use crate::value::CommonValue;
+use crate::{run_replay, Error, QueryReplay};
+
+/// The outcome of replaying a single query against multiple implementations
+#[derive(Debug)]
+pub struct Comparison {
+    /// The value stored in the replay
+    pub expected: CommonValue,
+    /// The value (or error) each implementation produced, in the order they were given
+    pub values: Vec<Result<CommonValue, Error>>,
+}
+
+impl Comparison {
+    /// Whether the implementation at `index` produced the expected value
+    pub fn matches_expected(&self, index: usize) -> bool {
+        matches!(&self.values[index], Ok(value) if value == &self.expected)
+    }
+
+    /// Whether two implementations produced the same value, errors never agree
+    pub fn agree(&self, a: usize, b: usize) -> bool {
+        match (&self.values[a], &self.values[b]) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Print the differences of each implementation from the expected value and from each other
+    pub fn print_differences(&self, names: &[String]) {
+        for (i, value) in self.values.iter().enumerate() {
+            match value {
+                Ok(value) if value != &self.expected => {
+                    println!("{} differs from expected:", names[i]);
+                    self.expected.print_difference(value);
+                }
+                Err(e) => println!("{} failed: {:?}", names[i], e),
+                _ => {}
+            }
+        }
+
+        for a in 0..self.values.len() {
+            for b in a + 1..self.values.len() {
+                if let (Ok(value_a), Ok(value_b)) = (&self.values[a], &self.values[b]) {
+                    if value_a != value_b {
+                        println!("{} differs from {}:", names[a], names[b]);
+                        value_a.print_difference(value_b);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Replay the same query against every implementation, with any request settings in `overrides`
+/// replacing those stored in the replay
+pub fn compare(
+    implementations: &[(String, Box<dyn QueryImplementation>)],
+    query_replay: &QueryReplay,
+    overrides: &RequestSettings,
+) -> Result<Comparison, Error> {
+    let mut query_replay = query_replay.clone();
+    query_replay.query.request.merge(overrides);
+
+    let mut values = Vec::with_capacity(implementations.len());
+
+    for (_, implementation) in implementations {
+        match run_replay(implementation.as_ref(), query_replay.clone()) {
+            Ok(outcome) => values.push(Ok(outcome.value)),
+            // A bad replay file is not the fault of any one implementation
+            Err(e @ Error::WrongReplayVersion { .. }) => return Err(e),
+            Err(e) => values.push(Err(e)),
+        }
+    }
+
+    Ok(Comparison {
+        expected: query_replay.value.clone(),
+        values,
+    })
+}
+
+/// Comparisons of a set of implementations across many replay files
+#[derive(Debug)]
+pub struct CompareMatrix {
+    pub implementations: Vec<String>,
+    pub rows: Vec<(PathBuf, Result<Comparison, Error>)>,
+}
+
+impl CompareMatrix {
+    /// Compare implementations across all replays found in the given paths (directories are
+    /// searched recursively), see [compare]
+    pub fn build(
+        implementations: &[(String, Box<dyn QueryImplementation>)],
+        paths: &[impl AsRef<Path>],
+        overrides: &RequestSettings,
+    ) -> Result<Self, Error> {
+        let mut rows = Vec::new();
+
+        for path in paths {
+            for file in crate::options::find_replays(path.as_ref())? {
+                println!("Comparing {}", file.display());
+                let comparison = QueryReplay::load(&file)
+                    .and_then(|replay| compare(implementations, &replay, overrides));
+                rows.push((file, comparison));
+            }
+        }
+
+        Ok(CompareMatrix {
+            implementations: implementations
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect(),
+            rows,
+        })
+    }
+
+    /// Count how many replays the implementations at `a` and `b` agreed on
+    pub fn agreement(&self, a: usize, b: usize) -> usize {
+        self.rows
+            .iter()
+            .filter(|(_, row)| matches!(row, Ok(comparison) if comparison.agree(a, b)))
+            .count()
+    }
+
+    /// Count how many replays the implementation at `index` produced the expected value for
+    pub fn passed(&self, index: usize) -> usize {
+        self.rows
+            .iter()
+            .filter(|(_, row)| matches!(row, Ok(comparison) if comparison.matches_expected(index)))
+            .count()
+    }
+
+    /// Print a table of each replay against each implementation followed by a parity summary
+    pub fn print(&self) {
+        let width = self
+            .rows
+            .iter()
+            .map(|(path, _)| path.display().to_string().len())
+            .max()
+            .unwrap_or(0)
+            .max("replay".len());
+
+        print!("{:<width$}", "replay");
+        for name in &self.implementations {
+            print!("  {:<8}", name);
+        }
+        println!();
+
+        for (path, row) in &self.rows {
+            print!("{:<width$}", path.display().to_string());
+            match row {
+                Ok(comparison) => {
+                    for (i, value) in comparison.values.iter().enumerate() {
+                        let cell = match value {
+                            Ok(_) if comparison.matches_expected(i) => "match",
+                            Ok(_) => "differs",
+                            Err(_) => "error",
+                        };
+                        print!("  {:<8}", cell);
+                    }
+                }
+                Err(e) => print!("  invalid replay: {:?}", e),
+            }
+            println!();
+        }
+
+        println!();
+        let total = self.rows.len();
+        for (i, name) in self.implementations.iter().enumerate() {
+            println!("{} matches expected: {}/{}", name, self.passed(i), total);
+        }
+        for a in 0..self.implementations.len() {
+            for b in a + 1..self.implementations.len() {
+                println!(
+                    "{} agrees with {}: {}/{}",
+                    self.implementations[a],
+                    self.implementations[b],
+                    self.agreement(a, b),
+                    total
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::{compare, CompareMatrix};
+    use crate::error::GenericError;
+    use crate::implementations::QueryImplementation;
+    use crate::mock::MockServer;
+    use crate::options::RequestSettings;
+    use crate::value::CommonValue;
+    use crate::{QueryOptions, QueryReplay};
+
+    /// Returns a fixed server name without sending anything, recording the request settings it
+    /// was given
+    struct NameImpl {
+        name: &'static str,
+        requests: Arc<Mutex<Vec<RequestSettings>>>,
+    }
+
+    impl QueryImplementation for NameImpl {
+        fn query_server(&self, options: &QueryOptions) -> Result<CommonValue, GenericError> {
+            self.requests.lock().unwrap().push(options.request.clone());
+            let mut value = MockServer::udp().into_replay().value;
+            value.name = Some(self.name.to_string());
+            Ok(value)
+        }
+    }
+
+    type Implementations = Vec<(String, Box<dyn QueryImplementation>)>;
+
+    fn implementations(
+        names: &[&'static str],
+    ) -> (Implementations, Arc<Mutex<Vec<RequestSettings>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let implementations = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let implementation: Box<dyn QueryImplementation> = Box::new(NameImpl {
+                    name,
+                    requests: requests.clone(),
+                });
+                (format!("impl{}", i), implementation)
+            })
+            .collect();
+        (implementations, requests)
+    }
+
+    fn replay(name: &str) -> QueryReplay {
+        let mut replay = MockServer::udp().into_replay();
+        replay.value.name = Some(name.to_string());
+        replay.query.request.timeout_ms = Some(500);
+        replay.query.request.retries = Some(2);
+        replay
+    }
+
+    #[test]
+    fn compare_implementations() {
+        let (implementations, requests) = implementations(&["expected", "expected", "other"]);
+        let overrides = RequestSettings {
+            timeout_ms: Some(100),
+            ..Default::default()
+        };
+
+        let comparison = compare(&implementations, &replay("expected"), &overrides).unwrap();
+        assert!(comparison.matches_expected(0));
+        assert!(comparison.matches_expected(1));
+        assert!(!comparison.matches_expected(2));
+        assert!(comparison.agree(0, 1));
+        assert!(!comparison.agree(1, 2));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        for request in requests.iter() {
+            assert_eq!(request.timeout_ms, Some(100));
+            assert_eq!(request.retries, Some(2));
+        }
+    }
+
+    #[test]
+    fn compare_matrix() {
+        let dir = std::env::temp_dir().join(format!("compare-matrix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        replay("a").save(dir.join("a.json")).unwrap();
+        replay("b").save(dir.join("b.json")).unwrap();
+        std::fs::write(dir.join("c.json"), "not a replay").unwrap();
+
+        let (implementations, _) = implementations(&["a", "b"]);
+        let matrix = CompareMatrix::build(&implementations, &[&dir], &Default::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let matrix = matrix.unwrap();
+
+        assert_eq!(matrix.implementations, vec!["impl0", "impl1"]);
+        assert_eq!(matrix.rows.len(), 3);
+        assert!(matrix.rows[2].1.is_err());
+        assert_eq!(matrix.passed(0), 1);
+        assert_eq!(matrix.passed(1), 1);
+        assert_eq!(matrix.agreement(0, 1), 0);
+    }
+}
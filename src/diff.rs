@@ -0,0 +1,417 @@
+//! Differences between two replays of the same query
+
+use std::ops::Range;
+
+use crate::inspect::hexdump;
+use crate::packet::Packet;
+use crate::value::FieldDifference;
+use crate::QueryReplay;
+
+/// Largest byte diff (left length * right length) computed exactly, larger packets are diffed as
+/// a single changed region between their common prefix and suffix
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// How a packet in one replay lines up with the other replay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketAlignment {
+    /// Packets at these indexes correspond to each other
+    Both(usize, usize),
+    /// Packet only in the left replay
+    Removed(usize),
+    /// Packet only in the right replay
+    Added(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Equal,
+    Insert,
+    Delete,
+    Replace,
+}
+
+/// A region of bytes in the left and right packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteChange {
+    pub kind: ChangeKind,
+    pub left: Range<usize>,
+    pub right: Range<usize>,
+}
+
+/// Differences between two replays
+#[derive(Debug, Clone)]
+pub struct ReplayDiff {
+    /// Differences in query options and server options
+    pub options: Vec<FieldDifference>,
+    /// Differences in the expected value
+    pub value: Vec<FieldDifference>,
+    pub packets: Vec<PacketAlignment>,
+}
+
+impl ReplayDiff {
+    pub fn new(left: &QueryReplay, right: &QueryReplay) -> Self {
+        let mut options = Vec::new();
+        let mut push = |field: &str, left: String, right: String| {
+            if left != right {
+                options.push(FieldDifference {
+                    field: field.to_string(),
+                    expected: left,
+                    value: right,
+                });
+            }
+        };
+        push(
+            "game",
+            format!("{:?}", left.query.game),
+            format!("{:?}", right.query.game),
+        );
+        push(
+            "port",
+            format!("{:?}", left.query.port),
+            format!("{:?}", right.query.port),
+        );
+        push(
+            "request",
+            format!("{:?}", left.query.request),
+            format!("{:?}", right.query.request),
+        );
+        push(
+            "tcp_port",
+            format!("{:?}", left.server.tcp_port),
+            format!("{:?}", right.server.tcp_port),
+        );
+        push(
+            "udp_port",
+            format!("{:?}", left.server.udp_port),
+            format!("{:?}", right.server.udp_port),
+        );
+        push(
+            "packet_size",
+            left.server.packet_size.to_string(),
+            right.server.packet_size.to_string(),
+        );
+        push(
+            "replay_version",
+            left.replay_version.to_string(),
+            right.replay_version.to_string(),
+        );
+
+        Self {
+            options,
+            value: left.value.differences(&right.value),
+            packets: align_packets(&left.packets, &right.packets),
+        }
+    }
+
+    /// Whether the replays have the same options, value and packet contents
+    pub fn is_empty(&self, left: &QueryReplay, right: &QueryReplay) -> bool {
+        self.options.is_empty()
+            && self.value.is_empty()
+            && self.packets.iter().all(|alignment| match alignment {
+                PacketAlignment::Both(l, r) => left.packets[*l].data == right.packets[*r].data,
+                _ => false,
+            })
+    }
+
+    /// Print the differences, packets that are the same in both replays are only listed
+    pub fn print(&self, left: &QueryReplay, right: &QueryReplay) {
+        for diff in self.options.iter() {
+            println!(
+                "option \"{}\" => left({}) right({})",
+                diff.field, diff.expected, diff.value
+            );
+        }
+        for diff in self.value.iter() {
+            println!(
+                "value \"{}\" => left({}) right({})",
+                diff.field, diff.expected, diff.value
+            );
+        }
+
+        for alignment in &self.packets {
+            match *alignment {
+                PacketAlignment::Both(l, r) => {
+                    let (left, right) = (&left.packets[l], &right.packets[r]);
+                    if left.data == right.data {
+                        println!("= #{} #{} {:?} {:?}", l, r, left.direction, left.protocol);
+                        continue;
+                    }
+                    println!(
+                        "~ #{} #{} {:?} {:?} ({} -> {} bytes)",
+                        l,
+                        r,
+                        left.direction,
+                        left.protocol,
+                        left.data.len(),
+                        right.data.len()
+                    );
+                    for change in diff_bytes(&left.data, &right.data) {
+                        print_change(&change, &left.data, &right.data);
+                    }
+                }
+                PacketAlignment::Removed(l) => {
+                    let packet = &left.packets[l];
+                    println!(
+                        "- #{} {:?} {:?} ({} bytes)",
+                        l,
+                        packet.direction,
+                        packet.protocol,
+                        packet.data.len()
+                    );
+                    print!("{}", hexdump(&packet.data));
+                }
+                PacketAlignment::Added(r) => {
+                    let packet = &right.packets[r];
+                    println!(
+                        "+ #{} {:?} {:?} ({} bytes)",
+                        r,
+                        packet.direction,
+                        packet.protocol,
+                        packet.data.len()
+                    );
+                    print!("{}", hexdump(&packet.data));
+                }
+            }
+        }
+    }
+}
+
+fn print_change(change: &ByteChange, left: &[u8], right: &[u8]) {
+    match change.kind {
+        ChangeKind::Equal => {}
+        ChangeKind::Insert => {
+            println!("  inserted at {}:", change.left.start);
+            print!("{}", hexdump(&right[change.right.clone()]));
+        }
+        ChangeKind::Delete => {
+            println!("  removed {:?}:", change.left);
+            print!("{}", hexdump(&left[change.left.clone()]));
+        }
+        ChangeKind::Replace => {
+            println!("  changed {:?} -> {:?}:", change.left, change.right);
+            print!("{}", hexdump(&left[change.left.clone()]));
+            print!("{}", hexdump(&right[change.right.clone()]));
+        }
+    }
+}
+
+/// Line up two packet sequences, packets can only correspond if they have the same direction and
+/// protocol, identical packets are preferred
+pub fn align_packets(left: &[Packet], right: &[Packet]) -> Vec<PacketAlignment> {
+    let score = |l: &Packet, r: &Packet| {
+        if l.direction != r.direction || l.protocol != r.protocol {
+            None
+        } else if l.data == r.data {
+            Some(2)
+        } else {
+            Some(1)
+        }
+    };
+
+    let (n, m) = (left.len(), right.len());
+    // best[i][j] is the best score aligning left[i..] with right[j..]
+    let mut best = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            let pair = score(&left[i], &right[j]).map_or(0, |s| s + best[i + 1][j + 1]);
+            best[i][j] = pair.max(best[i + 1][j]).max(best[i][j + 1]);
+        }
+    }
+
+    let mut alignment = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        match score(&left[i], &right[j]) {
+            Some(s) if best[i][j] == s + best[i + 1][j + 1] => {
+                alignment.push(PacketAlignment::Both(i, j));
+                i += 1;
+                j += 1;
+            }
+            _ if best[i][j] == best[i + 1][j] => {
+                alignment.push(PacketAlignment::Removed(i));
+                i += 1;
+            }
+            _ => {
+                alignment.push(PacketAlignment::Added(j));
+                j += 1;
+            }
+        }
+    }
+    alignment.extend((i..n).map(PacketAlignment::Removed));
+    alignment.extend((j..m).map(PacketAlignment::Added));
+    alignment
+}
+
+/// Byte level differences between two packets as regions covering both packets in order
+pub fn diff_bytes(left: &[u8], right: &[u8]) -> Vec<ByteChange> {
+    let prefix = left.iter().zip(right).take_while(|(l, r)| l == r).count();
+    let suffix = left[prefix..]
+        .iter()
+        .rev()
+        .zip(right[prefix..].iter().rev())
+        .take_while(|(l, r)| l == r)
+        .count();
+
+    let left_middle = prefix..left.len() - suffix;
+    let right_middle = prefix..right.len() - suffix;
+
+    let mut changes = Vec::new();
+    push_change(&mut changes, ChangeKind::Equal, 0..prefix, 0..prefix);
+
+    if left_middle.len().saturating_mul(right_middle.len()) > MAX_DIFF_CELLS {
+        push_change(
+            &mut changes,
+            ChangeKind::Replace,
+            left_middle.clone(),
+            right_middle.clone(),
+        );
+    } else {
+        diff_middle(
+            &mut changes,
+            &left[left_middle.clone()],
+            &right[right_middle.clone()],
+            prefix,
+        );
+    }
+
+    push_change(
+        &mut changes,
+        ChangeKind::Equal,
+        left_middle.end..left.len(),
+        right_middle.end..right.len(),
+    );
+    changes
+}
+
+/// Longest common subsequence diff of the bytes between the common prefix and suffix
+fn diff_middle(changes: &mut Vec<ByteChange>, left: &[u8], right: &[u8], offset: usize) {
+    let (n, m) = (left.len(), right.len());
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[at(i, j)] = if left[i] == right[j] {
+                lcs[at(i + 1, j + 1)] + 1
+            } else {
+                lcs[at(i + 1, j)].max(lcs[at(i, j + 1)])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        let (kind, di, dj) = if i < n && j < m && left[i] == right[j] {
+            (ChangeKind::Equal, 1, 1)
+        } else if j == m || (i < n && lcs[at(i + 1, j)] >= lcs[at(i, j + 1)]) {
+            (ChangeKind::Delete, 1, 0)
+        } else {
+            (ChangeKind::Insert, 0, 1)
+        };
+        push_change(
+            changes,
+            kind,
+            offset + i..offset + i + di,
+            offset + j..offset + j + dj,
+        );
+        i += di;
+        j += dj;
+    }
+}
+
+/// Add a region, merging it with the previous region where possible
+fn push_change(
+    changes: &mut Vec<ByteChange>,
+    kind: ChangeKind,
+    left: Range<usize>,
+    right: Range<usize>,
+) {
+    if left.is_empty() && right.is_empty() {
+        return;
+    }
+
+    if let Some(last) = changes.last_mut() {
+        let merged = match (last.kind, kind) {
+            (a, b) if a == b => Some(a),
+            (ChangeKind::Equal, _) | (_, ChangeKind::Equal) => None,
+            _ => Some(ChangeKind::Replace),
+        };
+        if let Some(merged) = merged {
+            last.kind = merged;
+            last.left.end = left.end;
+            last.right.end = right.end;
+            return;
+        }
+    }
+
+    changes.push(ByteChange { kind, left, right });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{align_packets, diff_bytes, ByteChange, ChangeKind, PacketAlignment};
+    use crate::packet::{Packet, PacketDirection, PacketProtocol};
+
+    #[test]
+    fn byte_changes() {
+        let change = |kind, left, right| ByteChange { kind, left, right };
+
+        assert_eq!(
+            diff_bytes(b"abcdef", b"abXdef"),
+            vec![
+                change(ChangeKind::Equal, 0..2, 0..2),
+                change(ChangeKind::Replace, 2..3, 2..3),
+                change(ChangeKind::Equal, 3..6, 3..6),
+            ]
+        );
+        assert_eq!(
+            diff_bytes(b"abcdef", b"abcXYdef"),
+            vec![
+                change(ChangeKind::Equal, 0..3, 0..3),
+                change(ChangeKind::Insert, 3..3, 3..5),
+                change(ChangeKind::Equal, 3..6, 5..8),
+            ]
+        );
+        assert_eq!(
+            diff_bytes(b"a1b2c", b"abc"),
+            vec![
+                change(ChangeKind::Equal, 0..1, 0..1),
+                change(ChangeKind::Delete, 1..2, 1..1),
+                change(ChangeKind::Equal, 2..3, 1..2),
+                change(ChangeKind::Delete, 3..4, 2..2),
+                change(ChangeKind::Equal, 4..5, 2..3),
+            ]
+        );
+        assert_eq!(diff_bytes(b"", b""), vec![]);
+    }
+
+    #[test]
+    fn packet_alignment() {
+        let packet = |direction, data: &[u8]| Packet {
+            direction,
+            protocol: PacketProtocol::Udp,
+            src_port: 0,
+            dst_port: 0,
+            data: data.to_vec(),
+        };
+        let left = [
+            packet(PacketDirection::ToServer, b"challenge"),
+            packet(PacketDirection::FromServer, b"1234"),
+            packet(PacketDirection::ToServer, b"info"),
+            packet(PacketDirection::FromServer, b"old info"),
+        ];
+        let right = [
+            packet(PacketDirection::ToServer, b"info"),
+            packet(PacketDirection::FromServer, b"new info"),
+        ];
+
+        assert_eq!(
+            align_packets(&left, &right),
+            vec![
+                PacketAlignment::Removed(0),
+                PacketAlignment::Removed(1),
+                PacketAlignment::Both(2, 0),
+                PacketAlignment::Both(3, 1),
+            ]
+        );
+    }
+}
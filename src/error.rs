@@ -18,6 +18,8 @@ pub enum Error {
     String(String),
     #[cfg(feature = "replay")]
     SendBeforeRecv(PacketProtocol),
+    #[cfg(feature = "replay")]
+    PacketMismatch(Vec<crate::server::PacketMismatch>),
     #[cfg(feature = "impl_rs")]
     Rust(gamedig::GDError),
     WrongReplayVersion {
@@ -26,6 +28,10 @@ pub enum Error {
     },
     #[cfg(feature = "filter")]
     Filter(crate::packet_filter::FilterError),
+    UnknownImplementation {
+        name: String,
+        valid: Vec<String>,
+    },
     Generic(GenericError),
 }
 
@@ -48,9 +54,12 @@ impl std::error::Error for Error {
             Self::ServerOptions(_) => None,
             #[cfg(feature = "replay")]
             Self::SendBeforeRecv(_) => None,
+            #[cfg(feature = "replay")]
+            Self::PacketMismatch(_) => None,
             #[cfg(feature = "filter")]
             Self::Filter(_) => None,
             Self::String(_) => None,
+            Self::UnknownImplementation { name: _, valid: _ } => None,
 
             #[cfg(feature = "capture")]
             Self::Pcap(source) => Some(source),
@@ -2,9 +2,9 @@
 use std::borrow::Cow;
 #[cfg(feature = "impl_rs")]
 use std::net::ToSocketAddrs;
-#[cfg(feature = "impl_node")]
+#[cfg(any(feature = "impl_node", feature = "impl_command"))]
 use std::path::PathBuf;
-#[cfg(feature = "impl_node")]
+#[cfg(any(feature = "impl_node", feature = "impl_command"))]
 use std::process::{Command, Stdio};
 use std::time::Duration;
 
@@ -16,6 +16,24 @@ pub trait QueryImplementation {
     fn query_server(&self, options: &QueryOptions) -> Result<CommonValue, GenericError>;
 }
 
+impl<T: QueryImplementation + ?Sized> QueryImplementation for Box<T> {
+    fn query_server(&self, options: &QueryOptions) -> Result<CommonValue, GenericError> {
+        self.as_ref().query_server(options)
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + 'a>>;
+
+/// Async version of [QueryImplementation], used with [crate::replay_async]
+#[cfg(feature = "tokio")]
+pub trait AsyncQueryImplementation {
+    fn query_server<'a>(
+        &'a self,
+        options: &'a QueryOptions,
+    ) -> BoxFuture<'a, Result<CommonValue, GenericError>>;
+}
+
 #[cfg(feature = "impl_rs")]
 #[derive(Debug)]
 pub struct RustImpl(gamedig::protocols::ExtraRequestSettings);
@@ -45,18 +63,35 @@ impl QueryImplementation for RustImpl {
             ))?
             .ip();
 
+        let request = &options.request;
+
+        let timeout = request
+            .timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::from_secs(5));
         let timeout_settings = gamedig::protocols::types::TimeoutSettings::new(
-            Some(Duration::from_secs(5)),
-            Some(Duration::from_secs(5)),
-            1,
+            Some(timeout),
+            Some(timeout),
+            request.retries.unwrap_or(1),
         )?;
 
+        let mut extra_settings = self.0.clone().set_hostname(options.address.clone());
+        if let Some(check_app_id) = request.check_app_id {
+            extra_settings = extra_settings.set_check_app_id(check_app_id);
+        }
+        if let Some(gather_players) = request.gather_players {
+            extra_settings = extra_settings.set_gather_players(gather_players);
+        }
+        if let Some(gather_rules) = request.gather_rules {
+            extra_settings = extra_settings.set_gather_rules(gather_rules);
+        }
+
         let output = gamedig::query_with_timeout_and_extra_settings(
             game,
             &ip,
             options.port,
             Some(timeout_settings),
-            Some(self.0.clone().set_hostname(options.address.clone())),
+            Some(extra_settings),
         )?;
 
         #[cfg(feature = "print_raw")]
@@ -66,6 +101,14 @@ impl QueryImplementation for RustImpl {
     }
 }
 
+#[cfg(feature = "impl_node")]
+mod node_worker;
+#[cfg(feature = "impl_node")]
+pub use node_worker::{NodeWorkerImpl, NodeWorkerOptions};
+
+mod registry;
+pub use registry::{ImplementationFactory, ImplementationOptions, ImplementationRegistry};
+
 #[cfg(feature = "impl_node")]
 #[derive(Debug)]
 pub struct NodeImpl {
@@ -84,6 +127,17 @@ impl Default for NodeImpl {
     }
 }
 #[cfg(feature = "impl_node")]
+impl NodeImpl {
+    /// Path to the node-gamedig package, found relative to the CLI script (bin/gamedig.js)
+    fn gamedig_package_path(&self) -> PathBuf {
+        self.gamedig_path
+            .parent()
+            .and_then(|bin| bin.parent())
+            .map(|package| package.to_path_buf())
+            .unwrap_or_else(|| ".".into())
+    }
+}
+#[cfg(feature = "impl_node")]
 impl QueryImplementation for NodeImpl {
     fn query_server(&self, options: &QueryOptions) -> Result<CommonValue, GenericError> {
         let mut host_str = Cow::from(&options.address);
@@ -101,8 +155,20 @@ impl QueryImplementation for NodeImpl {
         command
             .arg(&self.gamedig_path)
             .arg("--type")
-            .arg(&options.game)
-            .arg(host_str.as_ref());
+            .arg(&options.game);
+
+        // node-gamedig passes unknown CLI arguments through as query options
+        if let Some(timeout) = options.request.timeout_ms {
+            command.arg("--socketTimeout").arg(timeout.to_string());
+        }
+        if let Some(retries) = options.request.retries {
+            command.arg("--maxAttempts").arg((retries + 1).to_string());
+        }
+        if options.request.gather_rules == Some(true) {
+            command.arg("--requestRules");
+        }
+
+        command.arg(host_str.as_ref());
 
         println!("Running {:?}", command);
 
@@ -123,10 +189,133 @@ impl QueryImplementation for NodeImpl {
     }
 }
 
+/// Run an arbitrary executable to query a server, arguments are templates where `{game}`,
+/// `{address}`, `{port}` and `{host}` (address with optional port) are replaced with values from
+/// the query. If the query has no port any argument containing `{port}` is omitted.
+#[cfg(feature = "impl_command")]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct CommandImpl {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mapping: crate::value::FieldMapping,
+}
+#[cfg(feature = "impl_command")]
+impl CommandImpl {
+    pub fn new(program: impl Into<PathBuf>, args: Vec<String>) -> Self {
+        Self {
+            program: program.into(),
+            args,
+            mapping: Default::default(),
+        }
+    }
+
+    /// Substitute query options into the argument template
+    pub fn build_args(&self, options: &QueryOptions) -> Vec<String> {
+        let port = options.port.map(|port| port.to_string());
+        let host = match &port {
+            Some(port) => format!("{}:{}", options.address, port),
+            None => options.address.clone(),
+        };
+
+        self.args
+            .iter()
+            .filter(|arg| port.is_some() || !arg.contains("{port}"))
+            .map(|arg| {
+                arg.replace("{game}", &options.game)
+                    .replace("{address}", &options.address)
+                    .replace("{host}", &host)
+                    .replace("{port}", port.as_deref().unwrap_or_default())
+            })
+            .collect()
+    }
+}
+#[cfg(feature = "impl_command")]
+impl QueryImplementation for CommandImpl {
+    fn query_server(&self, options: &QueryOptions) -> Result<CommonValue, GenericError> {
+        let mut command = Command::new(&self.program);
+        command.stderr(Stdio::inherit());
+        command.args(self.build_args(options));
+
+        println!("Running {:?}", command);
+
+        let output = command.output()?;
+
+        if !output.status.success() {
+            return Err(Error::String(
+                String::from_utf8_lossy(&output.stdout).to_string(),
+            ))?;
+        }
+
+        let value: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+
+        #[cfg(feature = "print_raw")]
+        println!("{:#?}", value);
+
+        Ok(self.mapping.apply(&value)?)
+    }
+}
+
+/// Implementations provided by this crate, see [ImplementationRegistry] to look them up by name
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum Implementations {
     #[cfg(feature = "impl_node")]
     Node,
     #[cfg(feature = "impl_rs")]
     Rust,
+    #[cfg(feature = "impl_command")]
+    Command,
+}
+
+#[cfg(all(test, feature = "impl_command"))]
+mod test {
+    use super::{CommandImpl, ImplementationOptions, ImplementationRegistry, Implementations};
+    use crate::{Error, QueryOptions};
+
+    #[test]
+    fn unknown_implementation() {
+        let registry = ImplementationRegistry::default();
+        let err = registry
+            .create("python", &ImplementationOptions::default())
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, Error::UnknownImplementation { name, valid } if name == "python" && valid.contains(&"command".to_string()))
+        );
+
+        assert_eq!(
+            "command".parse::<Implementations>().unwrap(),
+            Implementations::Command
+        );
+    }
+
+    #[test]
+    fn command_args_template() {
+        let command = CommandImpl::new(
+            "query",
+            vec![
+                "--type={game}".to_string(),
+                "{host}".to_string(),
+                "--port={port}".to_string(),
+            ],
+        );
+        let mut options = QueryOptions {
+            address: "127.0.0.1".to_string(),
+            port: Some(27015),
+            game: "csgo".to_string(),
+            request: Default::default(),
+        };
+
+        assert_eq!(
+            command.build_args(&options),
+            vec!["--type=csgo", "127.0.0.1:27015", "--port=27015"]
+        );
+
+        options.port = None;
+        assert_eq!(
+            command.build_args(&options),
+            vec!["--type=csgo", "127.0.0.1"]
+        );
+    }
 }
@@ -0,0 +1,334 @@
+//! Long-lived node-gamedig process that answers queries over stdin/stdout as JSON lines
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{NodeImpl, QueryImplementation};
+use crate::error::GenericError;
+use crate::value::CommonValue;
+use crate::{Error, QueryOptions};
+
+/// Options for running node-gamedig queries in a persistent worker process
+#[derive(Debug, Clone)]
+pub struct NodeWorkerOptions {
+    /// Path to the worker script (scripts/gamedig-worker.js)
+    pub script_path: PathBuf,
+    /// How long to wait for a single query before the worker is restarted
+    pub timeout: Duration,
+}
+impl Default for NodeWorkerOptions {
+    fn default() -> Self {
+        Self {
+            script_path: "./scripts/gamedig-worker.js".into(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Runs node-gamedig queries in a long-lived worker process instead of starting node for every
+/// query, the worker is started on the first query and restarted if it crashes
+#[derive(Debug)]
+pub struct NodeWorkerImpl {
+    node: NodeImpl,
+    options: NodeWorkerOptions,
+    worker: Mutex<Option<NodeWorker>>,
+}
+
+impl NodeWorkerImpl {
+    /// Run the worker using node and node-gamedig from `node`
+    pub fn new(node: NodeImpl, options: NodeWorkerOptions) -> Self {
+        Self {
+            node,
+            options,
+            worker: Mutex::new(None),
+        }
+    }
+
+    fn query_worker(&self, options: &QueryOptions) -> Result<serde_json::Value, Error> {
+        let mut worker_process = self
+            .worker
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Retry once if the worker was found to have crashed (e.g. from a previous query)
+        let mut attempts = 2;
+        loop {
+            attempts -= 1;
+
+            let worker = match worker_process.as_mut() {
+                Some(worker) => worker,
+                None => worker_process.insert(NodeWorker::spawn(
+                    &self.node.node_path,
+                    self.node.node_args.as_deref().unwrap_or_default(),
+                    &self.options.script_path,
+                    &self.node.gamedig_package_path(),
+                )?),
+            };
+
+            match worker.query(options, self.options.timeout) {
+                Ok(value) => return Ok(value),
+                Err(WorkerError::Crashed(e)) if attempts > 0 => {
+                    println!("Node worker crashed ({:?}), restarting", e);
+                    *worker_process = None;
+                }
+                Err(e) => {
+                    // Don't reuse a worker that is in an unknown state
+                    *worker_process = None;
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+}
+
+impl QueryImplementation for NodeWorkerImpl {
+    fn query_server(&self, options: &QueryOptions) -> Result<CommonValue, GenericError> {
+        let value = self.query_worker(options)?;
+
+        #[cfg(feature = "print_raw")]
+        println!("{:#?}", value);
+
+        Ok(value.try_into()?)
+    }
+}
+
+#[derive(Debug)]
+pub enum WorkerError {
+    /// The worker exited or its pipes were closed
+    Crashed(std::io::Error),
+    /// The worker did not respond in time
+    Timeout,
+    /// The worker responded with something other than a response object
+    InvalidResponse(String),
+}
+
+impl From<WorkerError> for Error {
+    fn from(value: WorkerError) -> Self {
+        match value {
+            WorkerError::Crashed(e) => Error::IO(e),
+            WorkerError::Timeout => Error::String("Node worker query timed out".to_string()),
+            WorkerError::InvalidResponse(line) => {
+                Error::String(format!("Invalid node worker response: {}", line))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NodeWorker {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<std::io::Result<String>>,
+    next_id: u64,
+}
+
+impl NodeWorker {
+    pub fn spawn(
+        node_path: &Path,
+        node_args: &[String],
+        script_path: &Path,
+        gamedig_path: &Path,
+    ) -> Result<Self, Error> {
+        let mut command = Command::new(node_path);
+        command
+            .args(node_args)
+            .arg(script_path)
+            .arg(gamedig_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+
+        println!("Starting worker {:?}", command);
+
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().expect("stdin should be piped");
+        let stdout = child.stdout.take().expect("stdout should be piped");
+
+        // Read on a separate thread so that responses can be waited on with a timeout
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            child,
+            stdin,
+            lines,
+            next_id: 0,
+        })
+    }
+
+    pub fn query(
+        &mut self,
+        options: &QueryOptions,
+        timeout: Duration,
+    ) -> Result<serde_json::Value, WorkerError> {
+        self.next_id += 1;
+        let id = self.next_id;
+
+        let mut request = serde_json::json!({
+            "id": id,
+            "type": options.game,
+            "host": options.address,
+            "port": options.port,
+        });
+
+        // Same options as passed to the node-gamedig CLI
+        if let Some(timeout) = options.request.timeout_ms {
+            request["socketTimeout"] = timeout.into();
+        }
+        if let Some(retries) = options.request.retries {
+            request["maxAttempts"] = (retries + 1).into();
+        }
+        if options.request.gather_rules == Some(true) {
+            request["requestRules"] = true.into();
+        }
+
+        writeln!(self.stdin, "{}", request)
+            .and_then(|_| self.stdin.flush())
+            .map_err(WorkerError::Crashed)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let line = match self.lines.recv_timeout(remaining) {
+                Ok(line) => line.map_err(WorkerError::Crashed)?,
+                Err(RecvTimeoutError::Timeout) => return Err(WorkerError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(WorkerError::Crashed(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "worker closed stdout",
+                    )))
+                }
+            };
+
+            let response: serde_json::Value = serde_json::from_str(&line)
+                .map_err(|_| WorkerError::InvalidResponse(line.clone()))?;
+
+            // Skip responses to earlier queries
+            if response.get("id").and_then(|id| id.as_u64()) != Some(id) {
+                continue;
+            }
+
+            return match (response.get("result"), response.get("error")) {
+                (Some(result), _) => Ok(result.clone()),
+                // Keep node's error shape so it is handled like the CLI's output
+                (None, Some(error)) => Ok(serde_json::json!({ "error": error })),
+                (None, None) => Err(WorkerError::InvalidResponse(line)),
+            };
+        }
+    }
+}
+
+impl Drop for NodeWorker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::{NodeWorker, NodeWorkerImpl, NodeWorkerOptions};
+    use crate::implementations::NodeImpl;
+    use crate::options::RequestSettings;
+    use crate::QueryOptions;
+
+    /// Shell that stands in for node, the script is run with `sh -c` so the worker script and
+    /// gamedig paths become `$0` and `$1`
+    fn shell(script: &str) -> NodeImpl {
+        NodeImpl {
+            node_path: "sh".into(),
+            gamedig_path: "./node-gamedig/bin/gamedig.js".into(),
+            node_args: Some(vec!["-c".to_string(), script.to_string()]),
+        }
+    }
+
+    const READ_ID: &str = r#"id=$(echo "$line" | sed 's/.*"id":\([0-9]*\).*/\1/')"#;
+
+    fn options(game: &str) -> QueryOptions {
+        QueryOptions {
+            address: "127.0.0.1".to_string(),
+            port: Some(27015),
+            game: game.to_string(),
+            request: RequestSettings {
+                timeout_ms: Some(100),
+                retries: Some(2),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn worker_protocol() {
+        // Echoes each request back as the result after a response to an earlier query
+        let script = format!(
+            r#"while read -r line; do
+                {READ_ID}
+                echo '{{"id":0,"result":"stale"}}'
+                case "$line" in
+                    *'"type":"fail"'*) echo "{{\"id\":$id,\"error\":\"failed\"}}" ;;
+                    *) echo "{{\"id\":$id,\"result\":$line}}" ;;
+                esac
+            done"#
+        );
+        let node = shell(&script);
+        let mut worker = NodeWorker::spawn(
+            &node.node_path,
+            node.node_args.as_deref().unwrap(),
+            Path::new("worker.js"),
+            Path::new("node-gamedig"),
+        )
+        .unwrap();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            worker.query(&options("csgo"), timeout).unwrap(),
+            serde_json::json!({
+                "id": 1,
+                "type": "csgo",
+                "host": "127.0.0.1",
+                "port": 27015,
+                "socketTimeout": 100,
+                "maxAttempts": 3,
+            })
+        );
+        assert_eq!(
+            worker.query(&options("fail"), timeout).unwrap(),
+            serde_json::json!({ "error": "failed" })
+        );
+        assert_eq!(worker.query(&options("csgo"), timeout).unwrap()["id"], 3);
+    }
+
+    #[test]
+    fn worker_restarts_after_crash() {
+        // Answers a single query with its process ID then exits
+        let script = format!(
+            r#"read -r line
+            {READ_ID}
+            echo "{{\"id\":$id,\"result\":{{\"pid\":$$}}}}""#
+        );
+        let worker = NodeWorkerImpl::new(
+            shell(&script),
+            NodeWorkerOptions {
+                script_path: "worker.js".into(),
+                timeout: Duration::from_secs(5),
+            },
+        );
+
+        let first = worker.query_worker(&options("csgo")).unwrap();
+        let second = worker.query_worker(&options("csgo")).unwrap();
+        assert_ne!(first["pid"], second["pid"]);
+    }
+}
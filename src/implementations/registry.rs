@@ -0,0 +1,257 @@
+//! Lookup of implementations by name
+
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use super::*;
+
+/// Options used to configure implementations when they are created, keyed by option name (the
+/// CLI's long argument names e.g. `node-path`)
+#[derive(Debug, Clone, Default)]
+pub struct ImplementationOptions {
+    values: HashMap<String, Vec<String>>,
+}
+
+impl ImplementationOptions {
+    /// Add a value for an option, options can have multiple values
+    pub fn push(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.values
+            .entry(key.into())
+            .or_default()
+            .push(value.into());
+    }
+
+    /// Get the last value given for an option
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .get(key)
+            .and_then(|values| values.last())
+            .map(|value| value.as_str())
+    }
+
+    /// Get all values given for an option
+    pub fn get_all(&self, key: &str) -> &[String] {
+        self.values.get(key).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    /// Whether a flag option is set to "true"
+    pub fn flag(&self, key: &str) -> bool {
+        self.get(key) == Some("true")
+    }
+
+    /// Parse the last value given for an option
+    pub fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>, Error> {
+        self.get(key)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| Error::String(format!("Invalid value for {}: {:?}", key, value)))
+            })
+            .transpose()
+    }
+}
+
+pub type ImplementationFactory = Box<
+    dyn Fn(&ImplementationOptions) -> Result<Box<dyn QueryImplementation>, Error> + Send + Sync,
+>;
+
+/// Maps names to functions that create implementations, the default registry contains every
+/// implementation enabled by cargo features. Downstream crates can register their own.
+pub struct ImplementationRegistry {
+    factories: BTreeMap<String, ImplementationFactory>,
+}
+
+impl ImplementationRegistry {
+    /// Create a registry without any implementations
+    pub fn empty() -> Self {
+        Self {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// Add (or replace) an implementation
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        factory: impl Fn(&ImplementationOptions) -> Result<Box<dyn QueryImplementation>, Error>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.factories.insert(name.into(), Box::new(factory));
+    }
+
+    /// Names of all registered implementations (sorted)
+    pub fn names(&self) -> Vec<String> {
+        self.factories.keys().cloned().collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    /// Create an implementation by name
+    pub fn create(
+        &self,
+        name: &str,
+        options: &ImplementationOptions,
+    ) -> Result<Box<dyn QueryImplementation>, Error> {
+        let factory = self
+            .factories
+            .get(name)
+            .ok_or_else(|| Error::UnknownImplementation {
+                name: name.to_string(),
+                valid: self.names(),
+            })?;
+
+        factory(options)
+    }
+}
+
+impl Default for ImplementationRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        for implementation in Implementations::available() {
+            let implementation = implementation.clone();
+            registry.register(implementation.name(), move |options| {
+                implementation.create(options)
+            });
+        }
+        registry
+    }
+}
+
+impl std::fmt::Debug for ImplementationRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImplementationRegistry")
+            .field("names", &self.names())
+            .finish()
+    }
+}
+
+impl Implementations {
+    /// Implementations enabled by cargo features
+    pub fn available() -> &'static [Implementations] {
+        &[
+            #[cfg(feature = "impl_node")]
+            Implementations::Node,
+            #[cfg(feature = "impl_rs")]
+            Implementations::Rust,
+            #[cfg(feature = "impl_command")]
+            Implementations::Command,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "impl_node")]
+            Implementations::Node => "node",
+            #[cfg(feature = "impl_rs")]
+            Implementations::Rust => "rust",
+            #[cfg(feature = "impl_command")]
+            Implementations::Command => "command",
+        }
+    }
+
+    /// Create the implementation configured with options
+    pub fn create(
+        &self,
+        options: &ImplementationOptions,
+    ) -> Result<Box<dyn QueryImplementation>, Error> {
+        // Options are unused when no implementations are enabled
+        let _ = options;
+
+        match *self {
+            #[cfg(feature = "impl_node")]
+            Implementations::Node => {
+                let mut node = NodeImpl::default();
+                if let Some(node_path) = options.get("node-path") {
+                    node.node_path = node_path.into();
+                }
+                if let Some(gamedig_path) = options.get("node-gamedig-path") {
+                    node.gamedig_path = gamedig_path.into();
+                }
+                if !options.get_all("node-arg").is_empty() {
+                    node.node_args = Some(options.get_all("node-arg").to_vec());
+                }
+                if options.flag("node-worker") {
+                    let mut worker = NodeWorkerOptions::default();
+                    if let Some(script_path) = options.get("node-worker-script") {
+                        worker.script_path = script_path.into();
+                    }
+                    if let Some(timeout) = options.parse("node-timeout")? {
+                        worker.timeout = Duration::from_secs(timeout);
+                    }
+                    return Ok(Box::new(NodeWorkerImpl::new(node, worker)));
+                }
+                Ok(Box::new(node))
+            }
+            #[cfg(feature = "impl_rs")]
+            Implementations::Rust => Ok(Box::<RustImpl>::default()),
+            #[cfg(feature = "impl_command")]
+            Implementations::Command => {
+                let mut command = match options.get("command-config") {
+                    #[cfg(feature = "serde")]
+                    Some(config) => {
+                        let file = std::fs::File::open(config)?;
+                        serde_json::from_reader(std::io::BufReader::new(file))?
+                    }
+                    #[cfg(not(feature = "serde"))]
+                    Some(_) => {
+                        return Err(Error::String(
+                            "Command config requires the serde feature".to_string(),
+                        ))
+                    }
+                    None => CommandImpl::new(
+                        options.get("command").ok_or_else(|| {
+                            Error::String(
+                                "Command implementation requires a command or command config"
+                                    .to_string(),
+                            )
+                        })?,
+                        Vec::new(),
+                    ),
+                };
+                if let Some(program) = options.get("command") {
+                    command.program = program.into();
+                }
+                if !options.get_all("command-arg").is_empty() {
+                    command.args = options.get_all("command-arg").to_vec();
+                }
+                for mapping in options.get_all("command-map") {
+                    let (field, pointer) = mapping.split_once('=').ok_or_else(|| {
+                        Error::String(format!(
+                            "Command mapping should be FIELD=POINTER: {:?}",
+                            mapping
+                        ))
+                    })?;
+                    command.mapping.set(field, pointer)?;
+                }
+                Ok(Box::new(command))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Implementations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Implementations {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Implementations::available()
+            .iter()
+            .find(|implementation| implementation.name() == s)
+            .cloned()
+            .ok_or_else(|| Error::UnknownImplementation {
+                name: s.to_string(),
+                valid: Implementations::available()
+                    .iter()
+                    .map(|implementation| implementation.name().to_string())
+                    .collect(),
+            })
+    }
+}
@@ -0,0 +1,148 @@
+//! Human readable output of a replay file's contents
+
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+use crate::packet::{Packet, PacketDirection, PacketProtocol};
+use crate::{Error, QueryReplay};
+
+/// Which packets to show when inspecting a replay, unset filters match every packet
+#[derive(Debug, Clone, Default)]
+pub struct PacketSelection {
+    pub direction: Option<PacketDirection>,
+    pub protocol: Option<PacketProtocol>,
+    /// Packet indexes (starting at 0) to include
+    pub range: Option<RangeInclusive<usize>>,
+}
+
+impl PacketSelection {
+    pub fn matches(&self, index: usize, packet: &Packet) -> bool {
+        self.direction
+            .as_ref()
+            .map_or(true, |direction| direction == &packet.direction)
+            && self
+                .protocol
+                .as_ref()
+                .map_or(true, |protocol| protocol == &packet.protocol)
+            && self
+                .range
+                .as_ref()
+                .map_or(true, |range| range.contains(&index))
+    }
+}
+
+/// Parse a packet index range: "N", "N-M" (inclusive), "N-" or "-M"
+pub fn parse_range(range: &str) -> Result<RangeInclusive<usize>, Error> {
+    let invalid = || Error::String(format!("Invalid packet range {:?}", range));
+    let parse = |s: &str| s.trim().parse::<usize>().map_err(|_| invalid());
+
+    match range.split_once('-') {
+        None => {
+            let index = parse(range)?;
+            Ok(index..=index)
+        }
+        Some((start, end)) => {
+            let start = if start.trim().is_empty() {
+                0
+            } else {
+                parse(start)?
+            };
+            let end = if end.trim().is_empty() {
+                usize::MAX
+            } else {
+                parse(end)?
+            };
+            if start > end {
+                return Err(invalid());
+            }
+            Ok(start..=end)
+        }
+    }
+}
+
+/// Print a replay's metadata, server options, expected value and the selected packets
+pub fn print_replay(query_replay: &QueryReplay, selection: &PacketSelection) {
+    println!("Replay version: {}", query_replay.replay_version);
+    println!("Query: {:#?}", query_replay.query);
+    println!("Server: {:#?}", query_replay.server);
+    println!("Expected value: {:#?}", query_replay.value);
+
+    let shown = query_replay
+        .packets
+        .iter()
+        .enumerate()
+        .filter(|(index, packet)| selection.matches(*index, packet))
+        .count();
+    println!("\nPackets ({} of {}):", shown, query_replay.packets.len());
+
+    for (index, packet) in query_replay.packets.iter().enumerate() {
+        if !selection.matches(index, packet) {
+            continue;
+        }
+
+        println!(
+            "\n#{} {:?} {:?} {} -> {} ({} bytes)",
+            index,
+            packet.direction,
+            packet.protocol,
+            packet.src_port,
+            packet.dst_port,
+            packet.data.len()
+        );
+        print!("{}", hexdump(&packet.data));
+    }
+}
+
+/// Format data as lines of 16 bytes with the offset, hex bytes and printable ASCII
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x}  ", line * 16);
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(byte) => {
+                    let _ = write!(out, "{:02x} ", byte);
+                }
+                None => out.push_str("   "),
+            }
+            if i == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str(" |");
+        for byte in chunk {
+            out.push(if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            });
+        }
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hexdump, parse_range};
+
+    #[test]
+    fn hexdump_lines() {
+        assert_eq!(
+            hexdump(b"\xff\xff\xff\xffTSource Engine Query\x00"),
+            "00000000  ff ff ff ff 54 53 6f 75  72 63 65 20 45 6e 67 69  |....TSource Engi|\n\
+             00000010  6e 65 20 51 75 65 72 79  00                       |ne Query.|\n"
+        );
+        assert_eq!(hexdump(&[]), "");
+    }
+
+    #[test]
+    fn packet_ranges() {
+        assert_eq!(parse_range("3").unwrap(), 3..=3);
+        assert_eq!(parse_range("2-5").unwrap(), 2..=5);
+        assert_eq!(parse_range("4-").unwrap(), 4..=usize::MAX);
+        assert_eq!(parse_range("-1").unwrap(), 0..=1);
+        assert!(parse_range("5-2").is_err());
+        assert!(parse_range("a").is_err());
+    }
+}
@@ -22,15 +22,81 @@ pub mod packet;
 use packet::Packet;
 
 #[cfg(feature = "replay")]
-mod server;
+pub mod server;
+
+#[cfg(feature = "replay")]
+pub mod mock;
+#[cfg(feature = "tokio")]
+mod server_async;
 
 pub mod options;
 pub use options::{QueryOptions, QueryReplay};
 
 pub mod value;
 
+pub mod inspect;
+
+pub mod diff;
+
+pub mod pcap_export;
+
+#[cfg(all(feature = "replay", feature = "serde"))]
+pub mod compare;
+
+#[cfg(all(feature = "replay", feature = "serde"))]
+pub mod testing;
+
+#[cfg(all(feature = "replay", feature = "serde"))]
+pub mod batch;
+
+#[cfg(all(feature = "replay", feature = "serde"))]
+pub mod report;
+
+#[cfg(all(feature = "capture", feature = "serde"))]
+pub mod capture_list;
+
 pub const REPLAY_VERSION: u32 = 1;
 
+/// How long the replay server waits for each packet from an implementation, an implementation that
+/// doesn't send every captured packet is only reported once this has passed
+#[cfg(feature = "replay")]
+pub const REPLAY_SERVER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// BPF filter for a query's traffic: TCP and UDP to the server's address, and only to the query
+/// port if it is known. Without a port other flows to the host are dropped after the capture
+/// instead, see [query_packets].
+#[cfg(feature = "capture")]
+pub fn capture_filter(options: &QueryOptions) -> String {
+    match options.port {
+        Some(port) => format!(
+            "host {} and (udp port {} or tcp port {})",
+            options.address, port, port
+        ),
+        None => format!("host {} and (udp or tcp)", options.address),
+    }
+}
+
+/// Drop captured packets that aren't part of the query: flows without both a request and a
+/// response, and if the port isn't known every flow other than the first one sent to the server,
+/// e.g. an SSH session to the same host
+#[cfg(feature = "capture")]
+fn query_packets(options: &QueryOptions, packets: Vec<Packet>) -> Result<Vec<Packet>, Error> {
+    use packet_filter::transform::{KeepAnsweredFlows, KeepFirstFlow};
+
+    let captured = packets.len();
+    let mut packets = packet_filter::PacketTransform::transform(&mut KeepAnsweredFlows, packets)?;
+    if options.port.is_none() {
+        packets = packet_filter::PacketTransform::transform(&mut KeepFirstFlow, packets)?;
+    }
+    if packets.len() < captured {
+        println!(
+            "Dropped {} packets that weren't part of the query",
+            captured - packets.len()
+        );
+    }
+    Ok(packets)
+}
+
 #[cfg(feature = "capture")]
 fn create_pcap_capture(
     options: &QueryOptions,
@@ -49,7 +115,7 @@ fn create_pcap_capture(
     println!("Capturing using {:?}", device);
     let mut capture = Capture::from_device(device)?.immediate_mode(true).open()?;
 
-    let filter = format!("host {}", options.address);
+    let filter = capture_filter(options);
     println!("filter: {}", filter);
     capture.filter(&filter, true)?;
     Ok((addresses, capture))
@@ -57,7 +123,11 @@ fn create_pcap_capture(
 
 /// Capture a query using the given implementation, device name can be used to specify which
 /// network device to capture traffic on. To capture traffic this function requires elevated system
-/// privileges.
+/// privileges. If player names are censored they are replaced with pseudonyms generated from the
+/// seed (see [packet_filter::packet_name_replace]) and the replay is checked for the censored names
+/// afterwards (see [packet_filter::find_leaks]). Any that are still found are reported and the
+/// capture fails with [packet_filter::FilterError::Leaked] unless leaks are allowed. Names that
+/// weren't found in any packet are left as is with a warning.
 #[cfg(feature = "capture")]
 pub fn capture(
     implementation: Box<dyn QueryImplementation>,
@@ -65,6 +135,30 @@ pub fn capture(
     device_name: Option<&str>,
     pcap_file: Option<impl AsRef<Path>>,
     censor_player_names: bool,
+    pseudonym_seed: u64,
+    allow_leaks: bool,
+) -> Result<QueryReplay, Error> {
+    run_capture(
+        implementation.as_ref(),
+        options,
+        device_name,
+        pcap_file,
+        censor_player_names,
+        pseudonym_seed,
+        allow_leaks,
+    )
+}
+
+/// Capture a query using a borrowed implementation, see [capture]
+#[cfg(feature = "capture")]
+pub fn run_capture(
+    implementation: &dyn QueryImplementation,
+    options: QueryOptions,
+    device_name: Option<&str>,
+    pcap_file: Option<impl AsRef<Path>>,
+    censor_player_names: bool,
+    pseudonym_seed: u64,
+    allow_leaks: bool,
 ) -> Result<QueryReplay, Error> {
     let (addresses, mut capture) = create_pcap_capture(&options, device_name)?;
 
@@ -81,15 +175,24 @@ pub fn capture(
     let mut capture = capture.setnonblock()?;
 
     let mut packets = Vec::new();
+    let mut skipped = 0;
     while let Ok(packet) = capture.next_packet() {
         if let Some(ref mut save_file) = save_file {
             save_file.write(&packet); // Write to save file as backup
         }
 
-        let pkt = Packet::try_parse(packet.data, &addresses)?;
-        packets.push(pkt);
+        match Packet::try_parse(packet.data, &addresses) {
+            Ok(pkt) => packets.push(pkt),
+            Err(packet::PacketParseError::UnsupportedTransport) => skipped += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if skipped > 0 {
+        println!("Skipped {} packets with unsupported protocols", skipped);
     }
 
+    let packets = query_packets(&options, packets)?;
+
     let value = value?;
     println!("{:#?}", value);
     println!("{:?}", packets);
@@ -105,12 +208,71 @@ pub fn capture(
     };
 
     if censor_player_names {
-        packet_filter::packet_name_replace(&mut replay)?;
+        let names = replay.value.player_names.clone();
+        let not_found = packet_filter::packet_name_replace(&mut replay, pseudonym_seed)?;
+        for name in &not_found {
+            println!(
+                "Warning: player name {:?} was not found in any packet",
+                name
+            );
+        }
+
+        let censored = packet_filter::censored_names(&names, &replay);
+        let leaks = packet_filter::find_leaks(&replay, &censored);
+        if !leaks.is_empty() {
+            packet_filter::print_leak_report(&leaks);
+            if !allow_leaks {
+                return Err(packet_filter::FilterError::Leaked(leaks).into());
+            }
+        }
     }
 
     Ok(replay)
 }
 
+/// The result of replaying a saved query with an implementation
+#[cfg(feature = "replay")]
+#[derive(Debug, Clone)]
+pub struct ReplayOutcome {
+    /// The value stored in the replay
+    pub expected: value::CommonValue,
+    /// The value the implementation produced
+    pub value: value::CommonValue,
+    /// How long the implementation took to query the replay server
+    pub duration: std::time::Duration,
+    /// Packets the implementation sent that were different to the captured packets
+    pub packet_mismatches: Vec<server::PacketMismatch>,
+    /// Whether the server played back every packet in the replay
+    pub complete: bool,
+}
+
+#[cfg(feature = "replay")]
+impl ReplayOutcome {
+    /// Whether the produced value matches the stored value
+    pub fn matches(&self) -> bool {
+        self.value == self.expected
+    }
+
+    /// Whether the implementation sent every captured packet and they all matched
+    pub fn packets_match(&self) -> bool {
+        self.complete && self.packet_mismatches.is_empty()
+    }
+}
+
+/// Pick a loopback address for a replay server, each call returns a different address (until
+/// they wrap around) so that batches can run replays concurrently
+#[cfg(feature = "replay")]
+fn replay_address() -> IpAddr {
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    static NEXT: AtomicU16 = AtomicU16::new(0);
+
+    // Start at the historical 127.0.0.50 and skip addresses ending in 0 or 255
+    let n = NEXT.fetch_add(1, Ordering::Relaxed) % (254 * 254);
+    let n = n + 49;
+    IpAddr::V4(Ipv4Addr::new(127, 0, (n / 254) as u8, (n % 254) as u8 + 1))
+}
+
 /// Replay a saved query using a given implementation, return whether the output value (if
 /// successful) matches
 #[cfg(feature = "replay")]
@@ -118,8 +280,26 @@ pub fn replay(
     implementation: Box<dyn QueryImplementation>,
     query_replay: QueryReplay,
 ) -> Result<bool, Error> {
-    use std::sync::{Arc, Barrier};
+    let outcome = run_replay(implementation.as_ref(), query_replay)?;
+
+    let values_match = outcome.matches();
 
+    if !values_match {
+        outcome.expected.print_difference(&outcome.value);
+    }
+
+    println!("Took {:?}", outcome.duration);
+
+    Ok(values_match)
+}
+
+/// Replay a saved query using a given implementation, returning both the expected and produced
+/// values without printing anything
+#[cfg(feature = "replay")]
+pub fn run_replay(
+    implementation: &dyn QueryImplementation,
+    query_replay: QueryReplay,
+) -> Result<ReplayOutcome, Error> {
     if query_replay.replay_version != REPLAY_VERSION {
         return Err(Error::WrongReplayVersion {
             found: query_replay.replay_version,
@@ -127,38 +307,181 @@ pub fn replay(
         });
     }
 
-    let address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 50));
+    let address = replay_address();
 
     let mut query_options = query_replay.query.clone();
     let query_value = query_replay.value.clone();
 
-    let barrier = Arc::new(Barrier::new(2));
-
-    let server_barrier = Arc::clone(&barrier);
-    let server_thread = std::thread::spawn(move || {
-        server::server(address, query_replay, server_barrier).unwrap();
-    });
+    // Bind before starting the query so the server is ready to receive
+    let mut server = server::Server::bind(address, query_replay)?;
+    // Make sure the server thread eventually exits if the implementation stops early
+    server.set_timeout(Some(REPLAY_SERVER_TIMEOUT))?;
+    let server_thread = server.spawn_playback();
 
     query_options.address = address.to_string();
 
-    barrier.wait();
-
     let start_time = std::time::Instant::now();
     let value = implementation.query_server(&query_options)?;
     let duration = std::time::Instant::now() - start_time;
 
-    if !server_thread.is_finished() {
-        println!("WARNING: didn't consume all packets, leaking thread");
-        // FIXME: This leaks a thread, we should clean this up
+    // The server finishes once it has played back every packet, or times out waiting for a
+    // packet the implementation never sent
+    let (packet_mismatches, result) = server_thread.join().expect("Server thread panicked");
+    let complete = match result {
+        Ok(()) => true,
+        Err(e) if is_timeout(&e) => {
+            println!("WARNING: didn't consume all packets");
+            false
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(ReplayOutcome {
+        expected: query_value,
+        value,
+        duration,
+        packet_mismatches,
+        complete,
+    })
+}
+
+#[cfg(feature = "replay")]
+fn is_timeout(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Replay a saved query using an async implementation, the replay server runs on the current
+/// task so this can be used from `#[tokio::test]` without any extra threads
+#[cfg(feature = "tokio")]
+pub async fn replay_async(
+    implementation: &dyn implementations::AsyncQueryImplementation,
+    query_replay: QueryReplay,
+) -> Result<ReplayOutcome, Error> {
+    if query_replay.replay_version != REPLAY_VERSION {
+        return Err(Error::WrongReplayVersion {
+            found: query_replay.replay_version,
+            required: REPLAY_VERSION,
+        });
     }
 
-    let values_match = value == query_value;
+    let address = replay_address();
 
-    if !values_match {
-        query_value.print_difference(&value);
+    let mut query_options = query_replay.query.clone();
+    let query_value = query_replay.value.clone();
+
+    let server = server_async::AsyncServer::bind(address, query_replay).await?;
+
+    query_options.address = address.to_string();
+
+    // Stop waiting for the client eventually, like the threaded server
+    let server_task = tokio::time::timeout(REPLAY_SERVER_TIMEOUT, server.run());
+    tokio::pin!(server_task);
+
+    let start_time = std::time::Instant::now();
+    let mut query = implementation.query_server(&query_options);
+
+    let mut server_result = None;
+    let value = loop {
+        tokio::select! {
+            biased;
+            result = &mut server_task, if server_result.is_none() => match result {
+                // The query may never finish if the server failed to send it a packet
+                Ok(Err(e)) => return Err(e),
+                result => server_result = Some(result),
+            },
+            value = &mut query => break value?,
+        }
+    };
+    let duration = std::time::Instant::now() - start_time;
+
+    let server_result = match server_result {
+        Some(result) => result,
+        None => server_task.await,
+    };
+
+    let complete = server_result.is_ok();
+    let packet_mismatches = match server_result {
+        Ok(result) => result?,
+        Err(_elapsed) => {
+            println!("WARNING: didn't consume all packets");
+            Vec::new()
+        }
+    };
+
+    Ok(ReplayOutcome {
+        expected: query_value,
+        value,
+        duration,
+        packet_mismatches,
+        complete,
+    })
+}
+
+#[cfg(all(test, feature = "capture"))]
+mod test {
+    use super::{capture_filter, query_packets};
+    use crate::options::ServerOptions;
+    use crate::packet::{Packet, PacketDirection, PacketProtocol};
+    use crate::QueryOptions;
+
+    fn options(port: Option<u16>) -> QueryOptions {
+        QueryOptions {
+            address: "192.0.2.1".to_string(),
+            port,
+            game: "csgo".to_string(),
+            request: Default::default(),
+        }
     }
 
-    println!("Took {:?}", duration);
+    #[test]
+    fn filter() {
+        assert_eq!(
+            capture_filter(&options(Some(27015))),
+            "host 192.0.2.1 and (udp port 27015 or tcp port 27015)"
+        );
+        assert_eq!(
+            capture_filter(&options(None)),
+            "host 192.0.2.1 and (udp or tcp)"
+        );
+    }
 
-    Ok(values_match)
+    #[test]
+    fn query_packets_without_port() {
+        use PacketDirection::{FromServer, ToServer};
+        use PacketProtocol::{Tcp, Udp};
+
+        let packet = |direction, protocol, server_port, client_port, data: &[u8]| {
+            let (src_port, dst_port) = match direction {
+                PacketDirection::ToServer => (client_port, server_port),
+                PacketDirection::FromServer => (server_port, client_port),
+            };
+            Packet {
+                direction,
+                protocol,
+                src_port,
+                dst_port,
+                data: data.to_vec(),
+            }
+        };
+
+        // The query, an SSH session that is also answered and an unanswered packet
+        let packets = vec![
+            packet(ToServer, Udp, 27015, 5000, b"query"),
+            packet(FromServer, Tcp, 22, 6000, b"ssh banner"),
+            packet(ToServer, Tcp, 22, 6000, b"keystroke"),
+            packet(FromServer, Udp, 27015, 5000, b"response"),
+            packet(ToServer, Udp, 27016, 5001, b"unanswered"),
+        ];
+
+        let packets = query_packets(&options(None), packets).unwrap();
+        let data: Vec<&[u8]> = packets.iter().map(|packet| &packet.data[..]).collect();
+        assert_eq!(data, vec![&b"query"[..], b"response"]);
+
+        let server = ServerOptions::try_from(&packets[..]).unwrap();
+        assert_eq!(server.udp_port, Some(27015));
+        assert_eq!(server.tcp_port, None);
+    }
 }
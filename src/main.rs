@@ -1,7 +1,25 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use clap::{arg, value_parser, Command};
 
-use net_replay_test::{capture, replay, QueryOptions};
-use net_replay_test::{implementations::*, QueryReplay};
+use net_replay_test::batch::{exit_code, print_summary, run_batch, ReplayStatus};
+use net_replay_test::capture_list::{capture_list, load_server_list, CaptureListSettings};
+use net_replay_test::compare::CompareMatrix;
+use net_replay_test::diff::ReplayDiff;
+use net_replay_test::implementations::*;
+use net_replay_test::inspect::{parse_range, print_replay, PacketSelection};
+use net_replay_test::options::{find_replays, RequestSettings};
+use net_replay_test::packet::{PacketDirection, PacketProtocol};
+use net_replay_test::packet_filter::policy::RedactionPolicy;
+use net_replay_test::packet_filter::{
+    find_leaks, parse_seed, print_leak_report, CaptureRedaction, FilterError,
+    DEFAULT_PSEUDONYM_SEED,
+};
+use net_replay_test::pcap_export::export_pcap;
+use net_replay_test::report::Report;
+use net_replay_test::testing::{bless, BlessOutcome};
+use net_replay_test::{capture, Error, QueryOptions, QueryReplay};
 
 enum Mode {
     Capture,
@@ -20,92 +38,326 @@ impl TryFrom<String> for Mode {
 }
 
 fn main() {
+    let registry = ImplementationRegistry::default();
+
     let mut command = clap::command!()
-        .arg(arg!(-i --implementation <IMPL> "Optional implementation to use"))
+        .arg(
+            arg!(-i --implementation <IMPL> "Optional implementation to use").help(format!(
+                "Optional implementation to use ({}, default: node)",
+                registry.names().join(", ")
+            )),
+        )
         .arg(arg!(--"node-path" <PATH> "Optional path to node executable"))
         .arg(arg!(--"node-arg" <ARGS> ... "Optional additional arguments for node"))
         .arg(arg!(--"node-gamedig-path" <PATH> "Optional path to node-gamedig installation"))
+        .arg(
+            arg!(--"check-app-id" <BOOL> "Override whether the rust implementation checks app IDs")
+                .value_parser(value_parser!(bool)),
+        )
+        .arg(
+            arg!(--"gather-players" <BOOL> "Override whether players are requested")
+                .value_parser(value_parser!(bool)),
+        )
+        .arg(
+            arg!(--"gather-rules" <BOOL> "Override whether rules are requested")
+                .value_parser(value_parser!(bool)),
+        )
+        .arg(
+            arg!(--timeout <MILLISECONDS> "Override the query read/write timeout")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--retries <COUNT> "Override the number of query retries")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(arg!(--"node-worker" "Reuse a single node process for all queries"))
+        .arg(arg!(--"node-worker-script" <PATH> "Optional path to the node worker script"))
+        .arg(
+            arg!(--"node-timeout" <SECONDS> "Per-query timeout for the node worker")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(arg!(--command <PROGRAM> "Program to run for the command implementation"))
+        .arg(
+            arg!(--"command-arg" <ARG> ... "Argument template for the command implementation ({game}, {address}, {port}, {host})")
+                .allow_hyphen_values(true),
+        )
+        .arg(arg!(--"command-map" <MAPPING> ... "Map a value field to a JSON pointer in the command output (e.g. name=/info/name)"))
+        .arg(arg!(--"command-config" <FILE> "JSON file describing the command implementation"))
         .subcommand(
             Command::new("capture")
                 .about("Capture a new test (requires cap_net_raw,cap_net_admin=eip)")
-                .arg(arg!(<game> "Name of game (to query)"))
-                .arg(arg!(<address> "Hostname of server (to query)"))
+                .arg(arg!(<game> "Name of game (to query)").required_unless_present("list"))
+                .arg(arg!(<address> "Hostname of server (to query)").required_unless_present("list"))
                 .arg(arg!([port] "Optional port (to query)").value_parser(value_parser!(u16)))
-                .arg(arg!(--"censor-player-names" "Censor captured player names (naively)"))
+                .arg(arg!(--"censor-player-names" "Censor captured player names with pseudonyms of the same length"))
+                .arg(
+                    arg!(--"pseudonym-seed" <SEED> "Seed for --censor-player-names pseudonyms, a number or any string, keep it private or pseudonyms can be reversed")
+                        .requires("censor-player-names"),
+                )
+                .arg(
+                    arg!(--"censor-rule" <RULE> "Censor player names with pseudonyms of any length, fixing up length fields for the protocol")
+                        .value_parser(["auto", "raw", "source", "minecraft", "gamespy"])
+                        .conflicts_with("censor-player-names"),
+                )
+                .arg(arg!(--rules <FILE> "Apply a TOML or JSON redaction rules file to the capture"))
+                .arg(arg!(--"allow-leaks" "Save the replay even if redacted strings are still found in it"))
+                .arg(
+                    arg!(--redact "Redact player names, the server name, map and addresses in the replay and its file name (uses --censor-rule, default auto)")
+                        .conflicts_with("censor-player-names"),
+                )
                 .arg(arg!(-d --device <device> "Device to capture on"))
-                .arg(arg!(-c --capture "Save captured packets to a pcap file")),
+                .arg(arg!(-c --capture "Save captured packets to a pcap file"))
+                .arg(
+                    arg!(-l --list <FILE> "Capture every server in a CSV (game,address,port) or TOML server list")
+                        .conflicts_with_all(["game", "address", "port", "capture"]),
+                )
+                .arg(
+                    arg!(--delay <MS> "Minimum time between captures of a server list in milliseconds")
+                        .value_parser(value_parser!(u64))
+                        .default_value("1000")
+                        .requires("list"),
+                )
+                .arg(
+                    arg!(--"capture-retries" <N> "Times to retry a failed capture of a server list")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1")
+                        .requires("list"),
+                )
+                .arg(
+                    arg!(-o --output <DIR> "Directory to write a server list's replays and manifest to")
+                        .default_value(".")
+                        .requires("list"),
+                ),
         )
         .subcommand(
             Command::new("replay")
-                .about("Replay a captured test")
-                .arg(arg!(<file> "Capture file")),
+                .about("Replay captured tests, exits with 1 on mismatch, 2 on error and 3 on wrong replay version")
+                .arg(arg!(<files> ... "Capture files, directories of capture files or glob patterns"))
+                .arg(
+                    arg!(-j --jobs <JOBS> "Number of replays to run in parallel")
+                        .value_parser(value_parser!(usize))
+                        .default_value("1"),
+                )
+                .arg(arg!(--junit <FILE> "Write a JUnit XML report of the results"))
+                .arg(arg!(--json <FILE> "Write a JSON report of the results"))
+                .arg(
+                    arg!(--bless "Write the new value to the file if packets match but the value differs")
+                        .visible_alias("update")
+                        .conflicts_with_all(["jobs", "junit", "json"]),
+                ),
+        )
+        .subcommand(
+            Command::new("redact")
+                .about("Apply a redaction rules file to captured tests")
+                .arg(arg!(-r --rules <FILE> "TOML or JSON redaction rules file").required(true))
+                .arg(arg!(-o --output <FILE> "Write to this file instead of changing the capture file in place (only with one file)"))
+                .arg(arg!(--"allow-leaks" "Save the replay even if redacted strings are still found in it"))
+                .arg(arg!(<files> ... "Capture files or directories of capture files")),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .visible_alias("show")
+                .about("Print a captured test's options, expected value and packets")
+                .arg(arg!(<file> "Capture file"))
+                .arg(
+                    arg!(--direction <DIRECTION> "Only show packets sent in this direction")
+                        .value_parser(["to-server", "from-server"]),
+                )
+                .arg(
+                    arg!(--protocol <PROTOCOL> "Only show packets using this protocol")
+                        .value_parser(["tcp", "udp"]),
+                )
+                .arg(arg!(--packets <RANGE> "Only show packets in this index range (e.g. 3, 2-5, 4-)")),
+        )
+        .subcommand(
+            Command::new("export-pcap")
+                .about("Write a captured test's packets to a pcap file for Wireshark")
+                .arg(arg!(<file> "Capture file"))
+                .arg(arg!([output] "Pcap file to write (default: capture file with .pcap extension)")),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Show the differences between two captured tests, exits with 1 if they differ")
+                .arg(arg!(<left> "Capture file"))
+                .arg(arg!(<right> "Capture file")),
+        )
+        .subcommand(
+            Command::new("compare")
+                .about("Replay captured tests against multiple implementations and compare them")
+                .arg(
+                    arg!(-w --with <IMPL> ... "Implementations to compare (default: node and rust)"),
+                )
+                .arg(arg!(<paths> ... "Capture files or directories of capture files")),
         );
 
     let matches = command.clone().get_matches();
 
-    let implementation: Box<dyn QueryImplementation> =
-        if let Some(impl_name) = matches.get_one::<String>("implementation") {
-            match impl_name.as_str() {
-                "node" => {
-                    let mut node = NodeImpl::default();
-                    if let Some(node_path) = matches.get_one::<String>("node-path") {
-                        node.node_path = node_path.into();
-                    }
-                    if let Some(gamedig_path) = matches.get_one::<String>("node-gamedig-path") {
-                        node.gamedig_path = gamedig_path.into();
-                    }
-                    if let Some(node_args) = matches.get_many::<String>("node-arg") {
-                        node.node_args = Some(node_args.cloned().collect());
-                    }
-                    Box::new(node)
-                }
-                "rust" => Box::<RustImpl>::default(),
-                _ => panic!("No such impl {:?}", impl_name),
-            }
-        } else {
-            Box::<NodeImpl>::default()
-        };
+    let impl_name = matches
+        .get_one::<String>("implementation")
+        .map(|name| name.as_str())
+        .unwrap_or("node");
 
-    if let Some(matches) = matches.subcommand_matches("capture") {
-        do_capture(implementation, matches);
-    } else if let Some(matches) = matches.subcommand_matches("replay") {
-        do_replay(implementation, matches);
+    if let Some(sub_matches) = matches.subcommand_matches("capture") {
+        let implementation = create_implementation(&registry, impl_name, &matches);
+        if sub_matches.contains_id("list") {
+            do_capture_list(implementation.as_ref(), &matches, sub_matches);
+        } else {
+            do_capture(implementation, &matches, sub_matches);
+        }
+    } else if let Some(sub_matches) = matches.subcommand_matches("replay") {
+        do_replay(&registry, impl_name, &matches, sub_matches);
+    } else if let Some(sub_matches) = matches.subcommand_matches("redact") {
+        do_redact(sub_matches);
+    } else if let Some(sub_matches) = matches.subcommand_matches("inspect") {
+        do_inspect(sub_matches);
+    } else if let Some(sub_matches) = matches.subcommand_matches("export-pcap") {
+        do_export_pcap(sub_matches);
+    } else if let Some(sub_matches) = matches.subcommand_matches("diff") {
+        do_diff(sub_matches);
+    } else if let Some(sub_matches) = matches.subcommand_matches("compare") {
+        do_compare(&registry, &matches, sub_matches);
     } else {
         command.print_help().unwrap();
     }
 }
 
-fn do_capture(i: Box<dyn QueryImplementation>, matches: &clap::ArgMatches) {
+/// Collect the global arguments as options for creating implementations
+fn implementation_options(matches: &clap::ArgMatches) -> ImplementationOptions {
+    let mut options = ImplementationOptions::default();
+    for id in matches.ids() {
+        if let Ok(Some(values)) = matches.try_get_raw(id.as_str()) {
+            for value in values {
+                options.push(id.as_str(), value.to_string_lossy());
+            }
+        }
+    }
+    options
+}
+
+fn create_implementation(
+    registry: &ImplementationRegistry,
+    name: &str,
+    matches: &clap::ArgMatches,
+) -> Box<dyn QueryImplementation> {
+    match registry.create(name, &implementation_options(matches)) {
+        Ok(implementation) => implementation,
+        Err(Error::UnknownImplementation { name, valid }) => {
+            eprintln!(
+                "Unknown implementation {:?}, valid implementations are: {}",
+                name,
+                valid.join(", ")
+            );
+            std::process::exit(2);
+        }
+        Err(e) => panic!("Unable to create implementation {:?}: {:?}", name, e),
+    }
+}
+
+fn request_settings(matches: &clap::ArgMatches) -> RequestSettings {
+    RequestSettings {
+        check_app_id: matches.get_one::<bool>("check-app-id").copied(),
+        gather_players: matches.get_one::<bool>("gather-players").copied(),
+        gather_rules: matches.get_one::<bool>("gather-rules").copied(),
+        timeout_ms: matches.get_one::<u64>("timeout").copied(),
+        retries: matches.get_one::<usize>("retries").copied(),
+    }
+}
+
+fn pseudonym_seed(matches: &clap::ArgMatches) -> u64 {
+    matches
+        .get_one::<String>("pseudonym-seed")
+        .map(|seed| parse_seed(seed))
+        .unwrap_or(DEFAULT_PSEUDONYM_SEED)
+}
+
+/// Print a leak report and exit before the replay is written unless leaks are allowed
+fn check_leaks(query_replay: &QueryReplay, originals: &[Vec<u8>], allow_leaks: bool) {
+    let leaks = find_leaks(query_replay, originals);
+    if leaks.is_empty() {
+        return;
+    }
+
+    print_leak_report(&leaks);
+    if !allow_leaks {
+        eprintln!("Not saving the replay, use --allow-leaks to save it anyway");
+        std::process::exit(1);
+    }
+}
+
+/// Redaction of new captures from `--redact`, `--censor-rule` and `--rules`
+fn capture_redaction(matches: &clap::ArgMatches) -> CaptureRedaction {
+    CaptureRedaction {
+        redact: matches.get_flag("redact"),
+        censor_names: matches.contains_id("censor-rule"),
+        rule: match matches.get_one::<String>("censor-rule").map(String::as_str) {
+            None | Some("auto") => None,
+            Some(rule) => Some(rule.parse().unwrap()),
+        },
+        policy: matches
+            .get_one::<String>("rules")
+            .map(|rules| RedactionPolicy::load(rules).expect("Unable to load rules file")),
+    }
+}
+
+fn do_capture(
+    i: Box<dyn QueryImplementation>,
+    global_matches: &clap::ArgMatches,
+    matches: &clap::ArgMatches,
+) {
     let game = matches.get_one::<String>("game").unwrap();
     let address = matches.get_one::<String>("address").unwrap();
     let port = matches.get_one::<u16>("port");
     let device = matches.get_one::<String>("device");
     let should_save_pcap = matches.get_flag("capture");
     let censor_player_names = matches.get_flag("censor-player-names");
+    let pseudonym_seed = pseudonym_seed(matches);
 
     let opts = QueryOptions {
         game: game.to_string(),
         address: address.to_string(),
         port: port.copied(),
+        request: request_settings(global_matches),
     };
 
-    let replay_name = opts.as_file_name();
+    let redaction = capture_redaction(matches);
+    let replay_name = QueryOptions {
+        address: redaction.address(&opts.address),
+        ..opts.clone()
+    }
+    .as_file_name();
     let pcap_file = if should_save_pcap {
         Some(format!("{}.pcap", replay_name))
     } else {
         None
     };
 
+    let allow_leaks = matches.get_flag("allow-leaks");
     let r = capture(
         i,
         opts,
         device.map(|x| x.as_str()),
         pcap_file,
         censor_player_names,
+        pseudonym_seed,
+        allow_leaks,
     );
     println!("{:#?}", r);
 
-    let r = r.unwrap();
+    let mut r = match r {
+        Err(Error::Filter(FilterError::Leaked(_))) => {
+            eprintln!("Not saving the replay, use --allow-leaks to save it anyway");
+            std::process::exit(1);
+        }
+        r => r.unwrap(),
+    };
+
+    match redaction.apply(&mut r, allow_leaks) {
+        Err(Error::Filter(FilterError::Leaked(_))) => {
+            eprintln!("Not saving the replay, use --allow-leaks to save it anyway");
+            std::process::exit(1);
+        }
+        result => result.unwrap(),
+    }
 
     let file = std::fs::OpenOptions::new()
         .create_new(true)
@@ -116,20 +368,325 @@ fn do_capture(i: Box<dyn QueryImplementation>, matches: &clap::ArgMatches) {
     serde_json::to_writer(file, &r).unwrap();
 }
 
-fn do_replay(i: Box<dyn QueryImplementation>, matches: &clap::ArgMatches) {
+fn do_capture_list(
+    i: &dyn QueryImplementation,
+    global_matches: &clap::ArgMatches,
+    matches: &clap::ArgMatches,
+) {
+    let list = matches.get_one::<String>("list").unwrap();
+    let servers = load_server_list(list).expect("Unable to load server list");
+
+    let settings = CaptureListSettings {
+        output_dir: PathBuf::from(matches.get_one::<String>("output").unwrap()),
+        delay: Duration::from_millis(*matches.get_one::<u64>("delay").unwrap()),
+        retries: *matches.get_one::<usize>("capture-retries").unwrap(),
+        request: request_settings(global_matches),
+        device: matches.get_one::<String>("device").cloned(),
+        censor_player_names: matches.get_flag("censor-player-names"),
+        pseudonym_seed: pseudonym_seed(matches),
+        allow_leaks: matches.get_flag("allow-leaks"),
+        redaction: capture_redaction(matches),
+    };
+
+    let manifest = capture_list(i, servers, &settings).unwrap();
+
+    for entry in &manifest.entries {
+        match &entry.file {
+            Some(file) => println!("{} {}: {}", entry.game, entry.address, file.display()),
+            None => println!(
+                "{} {}: failed after {} attempt(s)",
+                entry.game, entry.address, entry.attempts
+            ),
+        }
+    }
+    println!(
+        "\n{} of {} captured",
+        manifest.succeeded(),
+        manifest.entries.len()
+    );
+
+    if manifest.succeeded() < manifest.entries.len() {
+        std::process::exit(1);
+    }
+}
+
+fn do_replay(
+    registry: &ImplementationRegistry,
+    impl_name: &str,
+    global_matches: &clap::ArgMatches,
+    matches: &clap::ArgMatches,
+) {
+    let files = replay_files(matches.get_many::<String>("files").unwrap());
+
+    if matches.get_flag("bless") {
+        let implementation = create_implementation(registry, impl_name, global_matches);
+        return do_bless(
+            implementation.as_ref(),
+            &files,
+            &request_settings(global_matches),
+        );
+    }
+
+    let jobs = *matches.get_one::<usize>("jobs").unwrap();
+    let options = implementation_options(global_matches);
+    let overrides = request_settings(global_matches);
+
+    let runs = run_batch(
+        &files,
+        &|| registry.create(impl_name, &options),
+        jobs,
+        &overrides,
+    );
+
+    for run in &runs {
+        if let Some(outcome) = run.outcome.as_ref().filter(|outcome| !outcome.matches()) {
+            println!("{}:", run.path.display());
+            outcome.expected.print_difference(&outcome.value);
+        }
+        if let Some(outcome) = run
+            .outcome
+            .as_ref()
+            .filter(|outcome| !outcome.packets_match())
+        {
+            println!(
+                "{}: {} packet(s) didn't match{}",
+                run.path.display(),
+                outcome.packet_mismatches.len(),
+                if outcome.complete {
+                    ""
+                } else {
+                    ", not all packets were played back"
+                }
+            );
+        }
+        if let Some(error) = &run.error {
+            println!("{}: {}", run.path.display(), error);
+        }
+    }
+
+    print_summary(&runs);
+
+    let report = Report::new(impl_name, &runs);
+    if let Some(path) = matches.get_one::<String>("junit") {
+        report
+            .write_junit(path)
+            .expect("Unable to write JUnit report");
+    }
+    if let Some(path) = matches.get_one::<String>("json") {
+        report
+            .write_json(path)
+            .expect("Unable to write JSON report");
+    }
+
+    std::process::exit(exit_code(&runs));
+}
+
+/// Expand replay arguments that are directories or glob patterns into replay files, exits if a
+/// pattern doesn't match anything
+fn replay_files<'a>(args: impl Iterator<Item = &'a String>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for arg in args {
+        let paths: Vec<PathBuf> = if Path::new(arg).exists() {
+            vec![PathBuf::from(arg)]
+        } else {
+            glob::glob(arg)
+                .unwrap_or_else(|e| panic!("Invalid glob pattern {:?}: {}", arg, e))
+                .filter_map(Result::ok)
+                .collect()
+        };
+
+        if paths.is_empty() {
+            eprintln!("No replays found matching {:?}", arg);
+            std::process::exit(2);
+        }
+
+        for path in paths {
+            files.extend(find_replays(&path).expect("Unable to read replay directory"));
+        }
+    }
+    files
+}
+
+fn do_redact(matches: &clap::ArgMatches) {
+    let rules = matches.get_one::<String>("rules").unwrap();
+    let policy = RedactionPolicy::load(rules).expect("Unable to load rules file");
+
+    let mut files = Vec::new();
+    for path in matches.get_many::<String>("files").unwrap() {
+        files.extend(find_replays(Path::new(path)).expect("Unable to read replay directory"));
+    }
+
+    let output = matches.get_one::<String>("output").map(PathBuf::from);
+    if output.is_some() && files.len() != 1 {
+        eprintln!("--output can only be used with a single capture file");
+        std::process::exit(2);
+    }
+
+    for file in &files {
+        let mut query_replay = QueryReplay::load(file).expect("Unable to load replay");
+        let originals: Vec<Vec<u8>> = policy
+            .replacements(&query_replay)
+            .unwrap()
+            .into_iter()
+            .map(|(original, _)| original)
+            .collect();
+        let count = policy.apply(&mut query_replay).unwrap();
+        check_leaks(&query_replay, &originals, matches.get_flag("allow-leaks"));
+
+        let output = output.as_ref().unwrap_or(file);
+        query_replay.save(output).expect("Unable to save replay");
+        println!(
+            "{}: {} replacements, written to {}",
+            file.display(),
+            count,
+            output.display()
+        );
+    }
+}
+
+fn do_inspect(matches: &clap::ArgMatches) {
     let file = matches.get_one::<String>("file").expect("Need file");
+    let query_replay = QueryReplay::load(file).expect("Unable to load replay");
 
-    let file = std::fs::OpenOptions::new()
-        .read(true)
-        .open(file)
-        .expect("File should exist");
+    let selection = PacketSelection {
+        direction: matches.get_one::<String>("direction").map(|direction| {
+            match direction.as_str() {
+                "to-server" => PacketDirection::ToServer,
+                _ => PacketDirection::FromServer,
+            }
+        }),
+        protocol: matches
+            .get_one::<String>("protocol")
+            .map(|protocol| match protocol.as_str() {
+                "tcp" => PacketProtocol::Tcp,
+                _ => PacketProtocol::Udp,
+            }),
+        range: matches
+            .get_one::<String>("packets")
+            .map(|range| parse_range(range).unwrap_or_else(|e| panic!("{:?}", e))),
+    };
+
+    print_replay(&query_replay, &selection);
+}
+
+fn do_export_pcap(matches: &clap::ArgMatches) {
+    let file = matches.get_one::<String>("file").expect("Need file");
+    let output = matches
+        .get_one::<String>("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(file).with_extension("pcap"));
+
+    let query_replay = QueryReplay::load(file).expect("Unable to load replay");
+    export_pcap(&query_replay, &output).expect("Unable to write pcap");
+
+    println!("Wrote {}", output.display());
+}
+
+fn do_diff(matches: &clap::ArgMatches) {
+    let load = |id| {
+        let file = matches.get_one::<String>(id).expect("Need file");
+        QueryReplay::load(file).expect("Unable to load replay")
+    };
+    let (left, right) = (load("left"), load("right"));
+
+    let diff = ReplayDiff::new(&left, &right);
+    diff.print(&left, &right);
+
+    if !diff.is_empty(&left, &right) {
+        std::process::exit(1);
+    }
+}
+
+fn do_compare(
+    registry: &ImplementationRegistry,
+    global_matches: &clap::ArgMatches,
+    matches: &clap::ArgMatches,
+) {
+    let names: Vec<String> = matches
+        .get_many::<String>("with")
+        .map(|names| names.cloned().collect())
+        .unwrap_or_else(|| vec!["node".to_string(), "rust".to_string()]);
+
+    let implementations: Vec<_> = names
+        .into_iter()
+        .map(|name| {
+            let implementation = create_implementation(registry, &name, global_matches);
+            (name, implementation)
+        })
+        .collect();
 
-    let query_replay: QueryReplay = serde_json::from_reader(file).expect("Invalid replay");
+    let paths: Vec<&String> = matches.get_many::<String>("paths").unwrap().collect();
+    let overrides = request_settings(global_matches);
 
-    let result = replay(i, query_replay).unwrap();
+    let matrix = CompareMatrix::build(&implementations, &paths, &overrides).unwrap();
+
+    for (path, row) in &matrix.rows {
+        if let Ok(comparison) = row {
+            if (0..implementations.len()).any(|i| !comparison.matches_expected(i)) {
+                println!("{}:", path.display());
+                comparison.print_differences(&matrix.implementations);
+            }
+        }
+    }
+
+    matrix.print();
+}
+
+fn do_bless(i: &dyn QueryImplementation, files: &[PathBuf], overrides: &RequestSettings) {
+    let mut statuses = Vec::with_capacity(files.len());
+    let mut errors = Vec::new();
+    for path in files {
+        let file = path.display();
+        let status = match bless(i, path, overrides) {
+            Ok(BlessOutcome::Unchanged) => {
+                println!("{}: unchanged", file);
+                ReplayStatus::Pass
+            }
+            Ok(BlessOutcome::Updated(differences)) => {
+                println!("{}: updated", file);
+                for diff in differences {
+                    println!(
+                        "  \"{}\" => old({}) new({})",
+                        diff.field, diff.expected, diff.value
+                    );
+                }
+                ReplayStatus::Pass
+            }
+            Ok(BlessOutcome::Refused(mismatches)) => {
+                println!(
+                    "{}: refused, {} packet(s) didn't match the capture",
+                    file,
+                    mismatches.len()
+                );
+                ReplayStatus::Mismatch
+            }
+            Err(e) => {
+                println!("{}: failed", file);
+                let status = if matches!(e, Error::WrongReplayVersion { .. }) {
+                    ReplayStatus::WrongVersion
+                } else {
+                    ReplayStatus::Error
+                };
+                errors.push((path, e));
+                status
+            }
+        };
+        statuses.push(status);
+    }
+
+    if !errors.is_empty() {
+        println!("\n{} replay(s) couldn't be blessed:", errors.len());
+        for (path, error) in &errors {
+            println!("{}: {:?}", path.display(), error);
+        }
+    }
 
-    if !result {
-        // If result didn't match make sure to error
-        panic!("Results didn't match");
+    let code = statuses
+        .iter()
+        .map(ReplayStatus::exit_code)
+        .max()
+        .unwrap_or(0);
+    if code > 0 {
+        std::process::exit(code);
     }
 }
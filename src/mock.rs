@@ -0,0 +1,231 @@
+//! Scripted replay servers for protocol unit tests, without needing a capture
+//!
+//! ```no_run
+//! # use net_replay_test::mock::MockServer;
+//! let server = MockServer::udp()
+//!     .expect(b"ping".to_vec())
+//!     .respond(b"pong".to_vec())
+//!     .start()
+//!     .unwrap();
+//!
+//! let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+//! socket.send_to(b"ping", server.address()).unwrap();
+//!
+//! server.finish().unwrap();
+//! ```
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::error::EResult;
+use crate::options::{QueryOptions, ServerOptions};
+use crate::packet::{Packet, PacketDirection, PacketProtocol};
+use crate::server::{PacketMismatch, Server};
+use crate::value::CommonValue;
+use crate::{Error, QueryReplay, REPLAY_VERSION};
+
+/// Builder for a server that expects and responds with a fixed sequence of packets
+#[derive(Debug, Clone)]
+pub struct MockServer {
+    protocol: PacketProtocol,
+    address: IpAddr,
+    port: u16,
+    packets: Vec<Packet>,
+    timeout: Option<Duration>,
+}
+
+impl MockServer {
+    pub fn new(protocol: PacketProtocol) -> Self {
+        Self {
+            protocol,
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            packets: Vec::new(),
+            timeout: Some(Duration::from_secs(5)),
+        }
+    }
+
+    pub fn udp() -> Self {
+        Self::new(PacketProtocol::Udp)
+    }
+
+    pub fn tcp() -> Self {
+        Self::new(PacketProtocol::Tcp)
+    }
+
+    /// Address to bind to (default 127.0.0.1)
+    pub fn address(mut self, address: IpAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Port to bind to (default is any free port)
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// How long to wait for each packet from the client (default 5 seconds)
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Expect the client to send a packet
+    pub fn expect(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.push(PacketDirection::ToServer, data.into());
+        self
+    }
+
+    /// Send a packet to the client
+    pub fn respond(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.push(PacketDirection::FromServer, data.into());
+        self
+    }
+
+    fn push(&mut self, direction: PacketDirection, data: Vec<u8>) {
+        self.packets.push(Packet {
+            direction,
+            protocol: self.protocol.clone(),
+            src_port: self.port,
+            dst_port: self.port,
+            data,
+        });
+    }
+
+    /// Build the equivalent replay, the expected value is empty
+    pub fn into_replay(self) -> QueryReplay {
+        let (tcp_port, udp_port) = match self.protocol {
+            PacketProtocol::Tcp => (Some(self.port), None),
+            PacketProtocol::Udp => (None, Some(self.port)),
+        };
+
+        QueryReplay {
+            query: QueryOptions {
+                address: self.address.to_string(),
+                port: Some(self.port),
+                game: "mock".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port,
+                udp_port,
+                packet_size: self
+                    .packets
+                    .iter()
+                    .map(|packet| packet.data.len())
+                    .max()
+                    .unwrap_or(0),
+            },
+            packets: self.packets,
+            value: CommonValue {
+                name: None,
+                map: None,
+                has_password: None,
+                players_online: None,
+                players_maximum: None,
+                player_names: Default::default(),
+            },
+            replay_version: REPLAY_VERSION,
+        }
+    }
+
+    /// Bind the server and start playing back packets on a new thread
+    pub fn start(self) -> EResult<MockServerHandle> {
+        let timeout = self.timeout;
+        let mut server = Server::bind(self.address, self.into_replay())?;
+        server.set_timeout(timeout)?;
+
+        let address = server
+            .udp_address()
+            .or_else(|| server.tcp_address())
+            .expect("Server should be bound to a port");
+
+        let thread = server.spawn();
+
+        Ok(MockServerHandle { address, thread })
+    }
+}
+
+/// A running mock server
+#[derive(Debug)]
+pub struct MockServerHandle {
+    address: SocketAddr,
+    thread: JoinHandle<std::io::Result<Vec<PacketMismatch>>>,
+}
+
+impl MockServerHandle {
+    /// Address the server is listening on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Wait for the server to play back all packets, failing if the client sent different packets
+    /// or the server timed out waiting for them
+    pub fn finish(self) -> EResult<()> {
+        let mismatches = self.thread.join().expect("Mock server thread panicked")?;
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PacketMismatch(mismatches))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpStream, UdpSocket};
+
+    use super::MockServer;
+    use crate::Error;
+
+    #[test]
+    fn mock_udp() {
+        let server = MockServer::udp()
+            .expect(b"ping".to_vec())
+            .respond(b"pong".to_vec())
+            .start()
+            .unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(b"ping", server.address()).unwrap();
+        let mut buf = [0; 4];
+        socket.recv(&mut buf).unwrap();
+
+        assert_eq!(&buf, b"pong");
+        server.finish().unwrap();
+    }
+
+    #[test]
+    fn mock_udp_mismatch() {
+        let server = MockServer::udp().expect(b"ping".to_vec()).start().unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(b"pong", server.address()).unwrap();
+
+        assert!(matches!(server.finish(), Err(Error::PacketMismatch(m)) if m.len() == 1));
+    }
+
+    #[test]
+    fn mock_tcp_stream() {
+        let server = MockServer::tcp()
+            .expect(b"hello ".to_vec())
+            .expect(b"world".to_vec())
+            .respond(b"ok".to_vec())
+            .start()
+            .unwrap();
+
+        // Segments split differently to the expected packets still match
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.write_all(b"hel").unwrap();
+        stream.flush().unwrap();
+        stream.write_all(b"lo world").unwrap();
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+
+        assert_eq!(&buf, b"ok");
+        server.finish().unwrap();
+    }
+}
@@ -9,6 +9,34 @@ pub struct QueryOptions {
     pub address: String,
     pub port: Option<u16>,
     pub game: String,
+    /// How the query was made, stored so that replays use the same settings as the capture
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub request: RequestSettings,
+}
+
+/// Settings passed through to implementations when querying, unset values use the
+/// implementation's defaults
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RequestSettings {
+    pub check_app_id: Option<bool>,
+    pub gather_players: Option<bool>,
+    pub gather_rules: Option<bool>,
+    /// Read and write timeout in milliseconds
+    pub timeout_ms: Option<u64>,
+    pub retries: Option<usize>,
+}
+
+impl RequestSettings {
+    /// Replace settings with any that are set in overrides
+    pub fn merge(&mut self, overrides: &RequestSettings) {
+        self.check_app_id = overrides.check_app_id.or(self.check_app_id);
+        self.gather_players = overrides.gather_players.or(self.gather_players);
+        self.gather_rules = overrides.gather_rules.or(self.gather_rules);
+        self.timeout_ms = overrides.timeout_ms.or(self.timeout_ms);
+        self.retries = overrides.retries.or(self.retries);
+    }
 }
 
 #[cfg(feature = "cli")]
@@ -85,3 +113,97 @@ pub struct QueryReplay {
     pub value: CommonValue,
     pub replay_version: u32,
 }
+
+#[cfg(feature = "serde")]
+impl QueryReplay {
+    /// Read a replay from a JSON file
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, crate::Error> {
+        let file = std::fs::OpenOptions::new().read(true).open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    /// Write a replay to a JSON file, replacing any existing file
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), crate::Error> {
+        let file = std::fs::File::create(path)?;
+        let mut writer = std::io::BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self)?;
+        std::io::Write::flush(&mut writer)?;
+        Ok(())
+    }
+}
+
+/// Find all replay files at a path, if the path is a directory it is searched recursively for
+/// `.json` files (in sorted order) otherwise the path itself is returned. JSON files without a
+/// `replay_version`, like capture manifests and reports, are skipped.
+pub fn find_replays(path: &std::path::Path) -> std::io::Result<Vec<std::path::PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    let mut replays = Vec::new();
+    for entry in entries {
+        if entry.is_dir() {
+            replays.extend(find_replays(&entry)?);
+        } else if entry.extension().is_some_and(|ext| ext == "json") && is_replay_file(&entry) {
+            replays.push(entry);
+        }
+    }
+
+    Ok(replays)
+}
+
+/// Whether a JSON file could be a replay, files that can't be read or parsed are kept so replaying
+/// them reports the error
+fn is_replay_file(path: &std::path::Path) -> bool {
+    let Ok(file) = std::fs::File::open(path) else {
+        return true;
+    };
+    match serde_json::from_reader::<_, serde_json::Value>(std::io::BufReader::new(file)) {
+        Ok(value) => value.get("replay_version").is_some(),
+        Err(_) => true,
+    }
+}
+
+#[cfg(all(test, feature = "replay", feature = "serde"))]
+mod test {
+    use super::find_replays;
+    use crate::mock::MockServer;
+
+    #[test]
+    fn find_replays_skips_other_json() {
+        let dir = std::env::temp_dir().join(format!("find-replays-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        MockServer::udp()
+            .into_replay()
+            .save(dir.join("a.json"))
+            .unwrap();
+        MockServer::udp()
+            .into_replay()
+            .save(dir.join("nested").join("b.json"))
+            .unwrap();
+        std::fs::write(dir.join("manifest.json"), r#"{"entries":[]}"#).unwrap();
+        std::fs::write(
+            dir.join("report.json"),
+            r#"{"implementation":"node","entries":[]}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("broken.json"), "not a replay").unwrap();
+
+        let replays = find_replays(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            replays.unwrap(),
+            vec![
+                dir.join("a.json"),
+                dir.join("broken.json"),
+                dir.join("nested").join("b.json"),
+            ]
+        );
+    }
+}
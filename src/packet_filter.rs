@@ -1,8 +1,26 @@
 //! Filtering primitives for packet data
 
-use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
 
-use crate::{packet::PacketDirection, QueryReplay};
+use crate::packet::{Packet, PacketDirection, PacketProtocol};
+use crate::value::CommonValue;
+use crate::QueryReplay;
+
+mod names;
+mod pseudonym;
+mod replacer;
+mod rules;
+pub mod transform;
+mod verify;
+pub use names::name_replacements;
+pub use pseudonym::{parse_seed, Pseudonyms, DEFAULT_PSEUDONYM_SEED};
+pub use replacer::Replacer;
+pub use rules::{ProtocolRule, ProtocolRules};
+pub use transform::{PacketTransform, Pipeline};
+pub use verify::{find_leaks, print_leak_report, verify_redaction, Leak, MIN_LEAK_LEN};
+
+#[cfg(feature = "policy")]
+pub mod policy;
 
 #[derive(Clone, Debug)]
 pub enum FilterError {
@@ -10,23 +28,26 @@ pub enum FilterError {
     EmptyReplace,
     /// Tried to replace with a different length replacement
     MismatchReplaceLen,
-    /// Expected to replace buffer but it was not found
-    ReplacementNotFound,
-}
-
-struct ReplacePtr {
-    start_pos: usize,
-    len: usize,
+    /// Replacement contains bytes that can't be used in the protocol
+    InvalidReplacement,
+    /// Packet couldn't be parsed by the protocol rule
+    MalformedPacket,
+    /// Packet uses a feature of the protocol that can't be rewritten (e.g. compression)
+    Unsupported,
+    /// Redacted strings are still present after redaction
+    Leaked(Vec<Leak>),
+    /// More addresses were found than there are documentation addresses to replace them with
+    TooManyAddresses,
+    /// Every pseudonym tried for a player name was already used, or two strings got the same
+    /// pseudonym from a rules file
+    PseudonymCollision,
 }
 
-impl ReplacePtr {
-    const fn new(start_pos: usize) -> Self {
-        Self { start_pos, len: 1 }
-    }
-}
+/// A pattern and the bytes to replace it with
+pub type Replacement = (Vec<u8>, Vec<u8>);
 
-// TODO: Test if replacing all replacements in one sweep is more efficient
-/// Replace raw bytes with other raw bytes
+/// Replace raw bytes with other raw bytes of the same length, overlapping matches are only
+/// replaced once (leftmost first). Use a [Replacer] to replace many patterns.
 pub fn raw_replace(
     buffer: &mut [u8],
     to_replace: &[u8],
@@ -39,34 +60,20 @@ pub fn raw_replace(
         return Err(FilterError::MismatchReplaceLen);
     }
 
-    let mut state: Vec<ReplacePtr> = Vec::with_capacity(buffer.len());
-    let mut matches: Vec<ReplacePtr> = Vec::with_capacity(buffer.len());
-
-    // Find matches
-    for (i, byte) in buffer.iter().enumerate() {
-        for j in (0..state.len()).rev() {
-            if to_replace[state[j].len].eq(byte) {
-                state[j].len += 1;
-                if state[j].len >= to_replace.len() {
-                    matches.push(state.swap_remove(j));
-                }
-            } else {
-                state.swap_remove(j);
-            }
-        }
-
-        if to_replace[0].eq(byte) {
-            state.push(ReplacePtr::new(i));
+    let mut count = 0;
+    let mut pos = 0;
+    while pos + to_replace.len() <= buffer.len() {
+        let section = &mut buffer[pos..pos + to_replace.len()];
+        if section == to_replace {
+            section.copy_from_slice(replacement);
+            count += 1;
+            pos += to_replace.len();
+        } else {
+            pos += 1;
         }
     }
 
-    for replace_match in &matches {
-        let section =
-            &mut buffer[replace_match.start_pos..replace_match.start_pos + replace_match.len];
-        section.copy_from_slice(replacement);
-    }
-
-    Ok(matches.len())
+    Ok(count)
 }
 
 /// Replace occurrences of to_replace in buffer with replacement
@@ -79,93 +86,659 @@ pub fn string_replace(
     raw_replace(buffer, to_replace.as_bytes(), replacement.as_bytes())
 }
 
-struct InfiniteSequence<'a, T> {
-    source: &'a [T],
-    pointers: Vec<usize>,
-    pos: usize,
+/// Replace patterns by checking each one at every position, where patterns overlap the first in
+/// the list wins. Used to check [Replacer] in tests.
+#[cfg(test)]
+fn naive_replace(buffer: &[u8], replacements: &[Replacement]) -> (Vec<u8>, Vec<usize>) {
+    let mut counts = vec![0; replacements.len()];
+    let mut output = Vec::with_capacity(buffer.len());
+
+    let mut pos = 0;
+    'outer: while pos < buffer.len() {
+        for (i, (to_replace, replacement)) in replacements.iter().enumerate() {
+            if !to_replace.is_empty() && buffer[pos..].starts_with(to_replace) {
+                output.extend_from_slice(replacement);
+                counts[i] += 1;
+                pos += to_replace.len();
+                continue 'outer;
+            }
+        }
+        output.push(buffer[pos]);
+        pos += 1;
+    }
+
+    (output, counts)
 }
 
-impl<'a, T: Clone> InfiniteSequence<'a, T> {
-    pub fn new(source: &'a [T]) -> InfiniteSequence<'a, T> {
-        assert!(!source.is_empty());
-        InfiniteSequence {
-            source,
-            pointers: vec![0],
-            pos: 0,
+/// Replace bytes in every packet from the server using a protocol rule to fix up length fields,
+/// so replacements may change the length of packets. Runs of TCP packets are rewritten together
+/// and split again at the original packet sizes, UDP packets the rule recognises as parts of a
+/// split message (see [ProtocolRule::split_id]) are rewritten together too. Returns how many
+/// times each pattern was replaced. The replay is left unchanged if any packet can't be rewritten.
+pub fn redact_replay(
+    query_replay: &mut QueryReplay,
+    replacer: &Replacer,
+    rule: &dyn ProtocolRule,
+) -> Result<Vec<usize>, FilterError> {
+    let (packets, counts) = redact_packets(query_replay.packets.clone(), replacer, rule)?;
+
+    query_replay.packets = packets;
+    query_replay.server.packet_size = query_replay
+        .packets
+        .iter()
+        .map(|packet| packet.data.len())
+        .max()
+        .unwrap_or(0);
+
+    Ok(counts)
+}
+
+/// Replace bytes in packets from the server, see [redact_replay]
+pub fn redact_packets(
+    packets: Vec<Packet>,
+    replacer: &Replacer,
+    rule: &dyn ProtocolRule,
+) -> Result<(Vec<Packet>, Vec<usize>), FilterError> {
+    for (_, replacement) in replacer.replacements() {
+        rule.check_replacement(replacement)?;
+    }
+
+    let mut counts = vec![0; replacer.len()];
+    let mut replace = |content: &[u8]| {
+        let (replaced, replaced_counts) = replacer.replace(content);
+        for (count, replaced) in counts.iter_mut().zip(replaced_counts) {
+            *count += replaced;
+        }
+        replaced
+    };
+
+    let mut old_packets = packets.into_iter().peekable();
+    let mut packets = Vec::with_capacity(old_packets.len());
+
+    while let Some(packet) = old_packets.next() {
+        if packet.direction != PacketDirection::FromServer {
+            packets.push(packet);
+            continue;
+        }
+
+        let mut message = vec![packet];
+        if message[0].protocol == PacketProtocol::Tcp {
+            while let Some(next) = old_packets.next_if(|next| {
+                next.direction == PacketDirection::FromServer
+                    && next.protocol == PacketProtocol::Tcp
+            }) {
+                message.push(next);
+            }
+        } else if let Some(id) = rule.split_id(&message[0].data) {
+            while let Some(next) = old_packets.next_if(|next| {
+                next.direction == PacketDirection::FromServer
+                    && next.protocol == PacketProtocol::Udp
+                    && rule.split_id(&next.data) == Some(id)
+            }) {
+                message.push(next);
+            }
+
+            let parts: Vec<&[u8]> = message.iter().map(|packet| &packet.data[..]).collect();
+            let rewritten = rule.rewrite_split(&parts, &mut replace)?;
+            packets.extend(rewritten.into_iter().map(|data| Packet {
+                data,
+                ..message[0].clone()
+            }));
+            continue;
         }
+
+        let data: Vec<u8> = message
+            .iter()
+            .flat_map(|packet| packet.data.iter().copied())
+            .collect();
+        let data = rule.rewrite(&data, &mut replace)?;
+
+        packets.extend(split_message(message, data));
     }
+
+    Ok((packets, counts))
 }
 
-impl<'a, T: Clone> Iterator for InfiniteSequence<'a, T> {
-    type Item = T;
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(pos) = self.pos.checked_sub(1) {
-            self.pos = pos;
+/// Split rewritten data back into packets with the same sizes as the original packets, the last
+/// packet takes any extra data and packets that would be empty are dropped
+fn split_message(message: Vec<Packet>, data: Vec<u8>) -> Vec<Packet> {
+    let count = message.len();
+    let mut remaining = &data[..];
+    let mut packets = Vec::with_capacity(count);
+
+    for (i, mut packet) in message.into_iter().enumerate() {
+        let size = if i + 1 == count {
+            remaining.len()
         } else {
-            self.pos = self.pointers.len() - 1;
+            packet.data.len().min(remaining.len())
+        };
+        if size == 0 && !packets.is_empty() {
+            continue;
+        }
+        packet.data = remaining[..size].to_vec();
+        remaining = &remaining[size..];
+        packets.push(packet);
+    }
+
+    packets
+}
+
+/// Replace all names in a query replay with pseudonyms of the same length generated from the
+/// seed (see [Pseudonyms]), names are found in the same forms as [name_replacements] and replaced
+/// in place. Returns the names that weren't found in any packet from the server, these are left
+/// as is.
+pub fn packet_name_replace(
+    query_replay: &mut QueryReplay,
+    seed: u64,
+) -> Result<Vec<String>, FilterError> {
+    let mut names: Vec<&String> = query_replay.value.player_names.iter().collect();
+    names.sort();
+
+    let mut pseudonyms = Pseudonyms::new(seed);
+    for name in &names {
+        pseudonyms.reserve(name);
+    }
+
+    let pseudonyms: Vec<(String, String)> = names
+        .into_iter()
+        .map(|name| {
+            let pseudonym = pseudonyms
+                .pseudonym(name)
+                .ok_or(FilterError::PseudonymCollision)?;
+            Ok((name.clone(), pseudonym))
+        })
+        .collect::<Result<_, FilterError>>()?;
+
+    // Every form of each name, replaced in a single pass over each packet
+    let server_data: Vec<&[u8]> = query_replay
+        .packets
+        .iter()
+        .filter(|packet| packet.direction == PacketDirection::FromServer)
+        .map(|packet| &packet.data[..])
+        .collect();
+    let mut replacements = Vec::new();
+    // Index of the name each replacement is for
+    let mut owners = Vec::new();
+    for (i, (name, pseudonym)) in pseudonyms.iter().enumerate() {
+        for (form, _) in name_replacements(name, pseudonym, &server_data, &ProtocolRules::Raw) {
+            if let Some(replacement) = names::same_length_replacement(&form, name, pseudonym) {
+                replacements.push((form, replacement));
+                owners.push(i);
+            }
         }
-        if self.pointers[self.pos] >= self.source.len() {
-            if self.pos == self.pointers.len() - 1 {
-                self.pointers.push(0);
+    }
+
+    let mut found = vec![0; pseudonyms.len()];
+    if !replacements.is_empty() {
+        let replacer = Replacer::new(&replacements)?;
+        for packet in query_replay
+            .packets
+            .iter_mut()
+            .filter(|packet| packet.direction == PacketDirection::FromServer)
+        {
+            let counts = replacer.replace_in_place(&mut packet.data)?;
+            for (owner, count) in owners.iter().zip(counts) {
+                found[*owner] += count;
             }
-            self.pointers[self.pos] = 0;
-        }
-        let r = self.source[self.pointers[self.pos]].clone();
-        if self.pos == 0 {
-            let mut i = 0;
-            loop {
-                if i >= self.pointers.len() {
-                    self.pointers.push(0);
-                    break;
-                }
-
-                self.pointers[i] += 1;
-                if self.pointers[i] >= self.source.len() {
-                    self.pointers[i] = 0;
-                } else {
-                    break;
-                }
-
-                i += 1;
+        }
+    }
+
+    let mut not_found = Vec::new();
+    query_replay.value.player_names = pseudonyms
+        .into_iter()
+        .zip(found)
+        .map(|((name, pseudonym), found)| {
+            if found == 0 {
+                not_found.push(name.clone());
+                name
+            } else {
+                pseudonym
             }
+        })
+        .collect();
+
+    Ok(not_found)
+}
+
+/// The names [packet_name_replace] replaced with a different string, to check for leaks. Names
+/// that weren't found, or made only of punctuation and so kept as is, are still in the replay.
+pub(crate) fn censored_names(
+    names: &std::collections::HashSet<String>,
+    query_replay: &QueryReplay,
+) -> Vec<Vec<u8>> {
+    names
+        .iter()
+        .filter(|name| !query_replay.value.player_names.contains(*name))
+        .map(|name| name.as_bytes().to_vec())
+        .collect()
+}
+
+/// The result of redacting a replay
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RedactionReport {
+    /// Number of replacements made in packets
+    pub replacements: usize,
+    /// Player names that couldn't be found in any packet from the server in any known encoding,
+    /// these are left as is in both the packets and the value
+    pub names_not_found: Vec<String>,
+}
+
+impl RedactionReport {
+    pub fn print(&self) {
+        println!("Made {} replacements", self.replacements);
+        for name in &self.names_not_found {
+            println!(
+                "Warning: player name {:?} was not found in any packet and was not redacted",
+                name
+            );
         }
-        Some(r)
     }
 }
 
-/// Replace all names in a query replay with censored version
-pub fn packet_name_replace(query_replay: &mut QueryReplay) -> Result<(), FilterError> {
-    let source = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ".as_bytes();
-    let mut generator_ = InfiniteSequence::new(source);
-    let generator = &mut generator_;
+/// Replace all player names in a query replay with "player1", "player2", ... using a protocol
+/// rule to fix up length fields, so that pseudonyms don't leak the length of each name. Names
+/// are also found in other encodings, see [name_replacements].
+pub fn packet_name_redact(
+    query_replay: &mut QueryReplay,
+    rule: &dyn ProtocolRule,
+) -> Result<RedactionReport, FilterError> {
+    let mut replacements = Vec::new();
+    let mut value_replacements = Vec::new();
+    let names_not_found = push_player_names(
+        &mut replacements,
+        &mut value_replacements,
+        query_replay,
+        rule,
+    );
+    sort_replacements(&mut replacements);
 
-    let mut name_replacements = HashMap::with_capacity(query_replay.value.player_names.len());
-    for name in &query_replay.value.player_names {
-        name_replacements.insert(name, generator.take(name.len()).collect::<Vec<_>>());
+    let value_replacer = Replacer::new(&value_replacements)?;
+    let counts = redact_replay(query_replay, &Replacer::new(&replacements)?, rule)?;
+    redact_value(&mut query_replay.value, &value_replacer);
+
+    Ok(RedactionReport {
+        replacements: counts.into_iter().sum(),
+        names_not_found,
+    })
+}
+
+/// What [privacy_redact] replaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivacyOptions {
+    /// Player names become "player1", "player2", ...
+    pub player_names: bool,
+    /// The server name becomes "Server"
+    pub server_name: bool,
+    /// The map name becomes "map"
+    pub map: bool,
+    /// The queried address and any IPv4 addresses in packets from the server become addresses
+    /// in 192.0.2.0/24, then 198.51.100.0/24 and 203.0.113.0/24 (redaction fails if there are
+    /// more than 762), a hostname becomes "server.invalid". IPv6 addresses are only replaced if
+    /// they are the queried address, they aren't found in packets.
+    pub addresses: bool,
+}
+
+impl Default for PrivacyOptions {
+    fn default() -> Self {
+        Self {
+            player_names: true,
+            server_name: true,
+            map: true,
+            addresses: true,
+        }
+    }
+}
+
+/// Address used in place of the queried address, see [PrivacyOptions::addresses]
+pub fn redacted_address(address: &str) -> String {
+    if address.parse::<IpAddr>().is_ok() {
+        Ipv4Addr::new(192, 0, 2, 1).to_string()
+    } else {
+        "server.invalid".to_string()
+    }
+}
+
+/// Networks reserved for documentation (RFC 5737) that addresses are replaced with
+const DOCUMENTATION_NETWORKS: [[u8; 3]; 3] = [[192, 0, 2], [198, 51, 100], [203, 0, 113]];
+
+/// The nth address in the documentation networks, skipping the network and broadcast addresses
+fn documentation_address(n: usize) -> Option<Ipv4Addr> {
+    let [a, b, c] = *DOCUMENTATION_NETWORKS.get(n / 254)?;
+    Some(Ipv4Addr::new(a, b, c, (n % 254) as u8 + 1))
+}
+
+/// Redact identifying information from the packets and expected value of a replay so that it can
+/// be published. Packets from the server are rewritten using the protocol rule, so replacements
+/// can change their length. Packets from the client are only changed in place, addresses in them
+/// are overwritten with the same number of "x"s. IP addresses are only found in text form.
+/// Player names are also found in other encodings, see [name_replacements].
+pub fn privacy_redact(
+    query_replay: &mut QueryReplay,
+    rule: &dyn ProtocolRule,
+    options: &PrivacyOptions,
+) -> Result<RedactionReport, FilterError> {
+    let mut replacements: Vec<Replacement> = Vec::new();
+    // Names are found in packets in other encodings, the value only needs the name itself
+    let mut name_replacements: Vec<Replacement> = Vec::new();
+    let mut value_name_replacements: Vec<Replacement> = Vec::new();
+
+    let mut names_not_found = Vec::new();
+    if options.player_names {
+        names_not_found = push_player_names(
+            &mut name_replacements,
+            &mut value_name_replacements,
+            query_replay,
+            rule,
+        );
     }
 
+    let value = &query_replay.value;
+    if options.server_name {
+        if let Some(name) = value.name.as_ref().filter(|name| !name.is_empty()) {
+            replacements.push((name.as_bytes().to_vec(), b"Server".to_vec()));
+        }
+    }
+    if options.map {
+        if let Some(map) = value.map.as_ref().filter(|map| !map.is_empty()) {
+            replacements.push((map.as_bytes().to_vec(), b"map".to_vec()));
+        }
+    }
+
+    let mut client_replacements = Vec::new();
+    if options.addresses {
+        let address = query_replay.query.address.clone();
+        let mut addresses = Vec::new();
+        if let Ok(IpAddr::V4(address)) = address.parse() {
+            addresses.push(address);
+        } else if address.parse::<IpAddr>().is_err() {
+            replacements.push((address.as_bytes().to_vec(), b"server.invalid".to_vec()));
+        }
+
+        for found in find_server_addresses(query_replay) {
+            if !addresses.contains(&found) {
+                addresses.push(found);
+            }
+        }
+
+        for (i, found) in addresses.iter().enumerate() {
+            let pseudonym = documentation_address(i).ok_or(FilterError::TooManyAddresses)?;
+            replacements.push((
+                found.to_string().into_bytes(),
+                pseudonym.to_string().into_bytes(),
+            ));
+        }
+
+        client_replacements.push(address);
+        client_replacements.extend(addresses.iter().map(|address| address.to_string()));
+    }
+
+    let mut value_replacements = replacements.clone();
+    value_replacements.extend(value_name_replacements);
+    sort_replacements(&mut value_replacements);
+    replacements.extend(name_replacements);
+    sort_replacements(&mut replacements);
+
+    let client_replacements: Vec<Replacement> = client_replacements
+        .into_iter()
+        .filter(|address| !address.is_empty())
+        .map(|address| (address.clone().into_bytes(), vec![b'x'; address.len()]))
+        .collect();
+
+    // Build everything that can fail before the replay is changed
+    let value_replacer = Replacer::new(&value_replacements)?;
+    let client_replacer = Replacer::new(&client_replacements)?;
+    let counts = redact_replay(query_replay, &Replacer::new(&replacements)?, rule)?;
+    redact_value(&mut query_replay.value, &value_replacer);
+
     for packet in query_replay
         .packets
         .iter_mut()
-        .filter(|packet| packet.direction == PacketDirection::FromServer)
+        .filter(|packet| packet.direction == PacketDirection::ToServer)
     {
-        for (name, replacement) in name_replacements.iter() {
-            raw_replace(&mut packet.data, name.as_bytes(), replacement)?;
+        client_replacer.replace_in_place(&mut packet.data)?;
+    }
+    if options.addresses {
+        query_replay.query.address = redacted_address(&query_replay.query.address);
+    }
+
+    Ok(RedactionReport {
+        replacements: counts.into_iter().sum(),
+        names_not_found,
+    })
+}
+
+/// The strings [privacy_redact] would replace, for checking the result with [verify_redaction]
+pub fn sensitive_strings(query_replay: &QueryReplay, options: &PrivacyOptions) -> Vec<Vec<u8>> {
+    let value = &query_replay.value;
+    let mut strings: Vec<Vec<u8>> = Vec::new();
+
+    if options.player_names {
+        strings.extend(
+            value
+                .player_names
+                .iter()
+                .map(|name| name.as_bytes().to_vec()),
+        );
+    }
+    if options.server_name {
+        strings.extend(value.name.iter().map(|name| name.as_bytes().to_vec()));
+    }
+    if options.map {
+        strings.extend(value.map.iter().map(|map| map.as_bytes().to_vec()));
+    }
+    if options.addresses {
+        strings.push(query_replay.query.address.as_bytes().to_vec());
+        strings.extend(
+            find_server_addresses(query_replay)
+                .into_iter()
+                .map(|address| address.to_string().into_bytes()),
+        );
+    }
+
+    strings.retain(|s| !s.is_empty());
+    strings.sort();
+    strings.dedup();
+    strings
+}
+
+/// Redaction applied to a replay after it is captured, shared by single and list captures
+#[derive(Debug, Clone, Default)]
+pub struct CaptureRedaction {
+    /// Replace everything in [PrivacyOptions] with [privacy_redact]
+    pub redact: bool,
+    /// Replace player names with [packet_name_redact], only used if `redact` isn't set
+    pub censor_names: bool,
+    /// Rule used to rewrite packets, [None] picks the rule for the replay's game
+    pub rule: Option<ProtocolRules>,
+    /// Rules file applied after the other redaction
+    #[cfg(feature = "policy")]
+    pub policy: Option<policy::RedactionPolicy>,
+}
+
+impl CaptureRedaction {
+    /// Redact a replay then check none of the replaced strings are left in it, any that are left
+    /// are printed and fail with [FilterError::Leaked] unless `allow_leaks` is set
+    pub fn apply(
+        &self,
+        query_replay: &mut QueryReplay,
+        allow_leaks: bool,
+    ) -> Result<(), crate::Error> {
+        let rule = self
+            .rule
+            .unwrap_or_else(|| ProtocolRules::for_game(&query_replay.query.game));
+
+        // Collect the originals before redacting to check they're gone afterwards
+        let mut originals = Vec::new();
+        if self.redact {
+            originals = sensitive_strings(query_replay, &PrivacyOptions::default());
+            println!("Redacting using the {} rule", rule);
+            privacy_redact(query_replay, &rule, &PrivacyOptions::default())?.print();
+        } else if self.censor_names {
+            originals = sensitive_strings(
+                query_replay,
+                &PrivacyOptions {
+                    player_names: true,
+                    server_name: false,
+                    map: false,
+                    addresses: false,
+                },
+            );
+            println!("Censoring player names using the {} rule", rule);
+            packet_name_redact(query_replay, &rule)?.print();
+        }
+
+        #[cfg(feature = "policy")]
+        if let Some(policy) = &self.policy {
+            let replacements = policy.replacements(query_replay)?;
+            originals.extend(replacements.into_iter().map(|(original, _)| original));
+            let count = policy.apply(query_replay)?;
+            println!("Made {} replacements from the rules file", count);
+        }
+
+        let leaks = find_leaks(query_replay, &originals);
+        if !leaks.is_empty() {
+            print_leak_report(&leaks);
+            if !allow_leaks {
+                return Err(FilterError::Leaked(leaks).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The address a query will have once redacted, for naming replay files
+    pub fn address(&self, address: &str) -> String {
+        if self.redact {
+            redacted_address(address)
+        } else {
+            address.to_string()
         }
     }
+}
+
+/// IPv4 addresses written in packets from the server or in the strings of the expected value
+fn find_server_addresses(query_replay: &QueryReplay) -> Vec<Ipv4Addr> {
+    let value = &query_replay.value;
+    let server_data = query_replay
+        .packets
+        .iter()
+        .filter(|packet| packet.direction == PacketDirection::FromServer)
+        .map(|packet| &packet.data[..]);
+    let value_strings = value
+        .name
+        .iter()
+        .chain(value.map.iter())
+        .chain(value.player_names.iter())
+        .map(|s| s.as_bytes());
+
+    let mut addresses = Vec::new();
+    for data in server_data.chain(value_strings) {
+        for found in find_ipv4_addresses(data) {
+            if !addresses.contains(&found) {
+                addresses.push(found);
+            }
+        }
+    }
+    addresses
+}
 
-    query_replay.value.player_names = name_replacements
-        .into_values()
-        .map(|name| String::from_utf8_lossy(&name).into_owned())
+/// Sort replacements longest first so strings containing other strings are replaced whole,
+/// duplicate patterns are removed
+fn sort_replacements(replacements: &mut Vec<Replacement>) {
+    replacements.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(&b.0)));
+    replacements.dedup_by(|a, b| a.0 == b.0);
+}
+
+/// Add replacements for every form each player name is found in packets from the server, and a
+/// replacement of each found name for the value. Pseudonyms are numbered longest name first.
+/// Returns the names that weren't found.
+fn push_player_names(
+    replacements: &mut Vec<Replacement>,
+    value_replacements: &mut Vec<Replacement>,
+    query_replay: &QueryReplay,
+    rule: &dyn ProtocolRule,
+) -> Vec<String> {
+    let mut names: Vec<&String> = query_replay
+        .value
+        .player_names
+        .iter()
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+
+    let server_data: Vec<&[u8]> = query_replay
+        .packets
+        .iter()
+        .filter(|packet| packet.direction == PacketDirection::FromServer)
+        .map(|packet| &packet.data[..])
         .collect();
 
-    Ok(())
+    let mut not_found = Vec::new();
+    for (i, name) in names.into_iter().enumerate() {
+        let pseudonym = format!("player{}", i + 1);
+        let forms = name_replacements(name, &pseudonym, &server_data, rule);
+        if forms.is_empty() {
+            not_found.push(name.clone());
+            continue;
+        }
+        replacements.extend(forms);
+        value_replacements.push((name.as_bytes().to_vec(), pseudonym.into_bytes()));
+    }
+    not_found
+}
+
+/// Apply replacements to the strings in a value so that it matches the redacted packets
+fn redact_value(value: &mut CommonValue, replacer: &Replacer) {
+    let redact = |s: &str| {
+        let (redacted, _) = replacer.replace(s.as_bytes());
+        String::from_utf8_lossy(&redacted).into_owned()
+    };
+
+    value.name = value.name.as_deref().map(redact);
+    value.map = value.map.as_deref().map(redact);
+    value.player_names = value.player_names.iter().map(|name| redact(name)).collect();
+}
+
+/// Find IPv4 addresses written as text (e.g. "203.0.113.7"), loopback and unspecified addresses
+/// are ignored
+pub fn find_ipv4_addresses(data: &[u8]) -> Vec<Ipv4Addr> {
+    let is_address_byte = |byte: &u8| byte.is_ascii_digit() || *byte == b'.';
+
+    let mut found = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if !data[pos].is_ascii_digit() || (pos > 0 && is_address_byte(&data[pos - 1])) {
+            pos += 1;
+            continue;
+        }
+
+        let len = data[pos..]
+            .iter()
+            .take_while(|b| is_address_byte(b))
+            .count();
+        let text = std::str::from_utf8(&data[pos..pos + len]).unwrap_or_default();
+        // Sentences can end with an address
+        let text = text.strip_suffix('.').unwrap_or(text);
+        if let Ok(address) = text.parse::<Ipv4Addr>() {
+            if !address.is_loopback() && !address.is_unspecified() && !found.contains(&address) {
+                found.push(address);
+            }
+        }
+        pos += len;
+    }
+    found
 }
 
 #[cfg(test)]
 mod test {
-    use super::string_replace;
-    use super::InfiniteSequence;
+    use std::net::Ipv4Addr;
+
+    use super::{censored_names, documentation_address, find_ipv4_addresses, find_leaks};
+    use super::{packet_name_replace, privacy_redact, split_message, string_replace, Replacer};
+    use super::{PrivacyOptions, ProtocolRules};
+    use crate::options::{QueryOptions, ServerOptions};
+    use crate::packet::{Packet, PacketDirection, PacketProtocol};
+    use crate::value::CommonValue;
+    use crate::{QueryReplay, REPLAY_VERSION};
 
     #[test]
     fn replace_string() {
@@ -176,11 +749,225 @@ mod test {
     }
 
     #[test]
-    fn test_infinite() {
-        let source = [0, 1, 2];
-        let inf = InfiniteSequence::new(&source);
+    fn replace_resize() {
+        let replacements = [
+            (b"Bobby".to_vec(), b"player1".to_vec()),
+            (b"Bob".to_vec(), b"player2".to_vec()),
+        ];
+        let replacer = Replacer::new(&replacements).unwrap();
+        let (replaced, counts) = replacer.replace(b"\x01Bob\0Bobby\0");
+        assert_eq!(replaced, b"\x01player2\0player1\0");
+        assert_eq!(counts, vec![1, 1]);
+    }
+
+    #[test]
+    fn split_rewritten_message() {
+        let packet = |data: &[u8]| Packet {
+            direction: PacketDirection::FromServer,
+            protocol: PacketProtocol::Tcp,
+            src_port: 25565,
+            dst_port: 40000,
+            data: data.to_vec(),
+        };
+
+        let split = split_message(vec![packet(b"abc"), packet(b"def")], b"abcdefgh".to_vec());
+        assert_eq!(split[0].data, b"abc");
+        assert_eq!(split[1].data, b"defgh");
+
+        let split = split_message(vec![packet(b"abc"), packet(b"def")], b"ab".to_vec());
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[0].data, b"ab");
+    }
+
+    #[test]
+    fn find_addresses() {
+        assert_eq!(
+            find_ipv4_addresses(b"tv 203.0.113.7:27020\x00127.0.0.1\x001.2.3.4.5 at 198.51.100.2."),
+            vec![
+                Ipv4Addr::new(203, 0, 113, 7),
+                Ipv4Addr::new(198, 51, 100, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn documentation_addresses() {
+        assert_eq!(documentation_address(0), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(
+            documentation_address(253),
+            Some(Ipv4Addr::new(192, 0, 2, 254))
+        );
+        assert_eq!(
+            documentation_address(254),
+            Some(Ipv4Addr::new(198, 51, 100, 1))
+        );
+        assert_eq!(
+            documentation_address(761),
+            Some(Ipv4Addr::new(203, 0, 113, 254))
+        );
+        assert_eq!(documentation_address(762), None);
+    }
+
+    #[test]
+    fn name_replace_forms() {
+        let mut replay = QueryReplay {
+            query: QueryOptions {
+                address: "127.0.0.1".to_string(),
+                port: Some(27960),
+                game: "q3a".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: None,
+                udp_port: Some(27960),
+                packet_size: 0,
+            },
+            packets: vec![Packet {
+                direction: PacketDirection::FromServer,
+                protocol: PacketProtocol::Udp,
+                src_port: 0,
+                dst_port: 0,
+                // Latin-1 and with color codes
+                data: b"0 0 \"Jos\xe9\"\n0 0 \"^1Bo^7b\"\n".to_vec(),
+            }],
+            value: CommonValue {
+                name: None,
+                map: None,
+                has_password: None,
+                players_online: Some(3),
+                players_maximum: None,
+                player_names: ["Jos\u{e9}", "Bob", "Mallory"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+
+        let not_found = packet_name_replace(&mut replay, 1).unwrap();
+        assert_eq!(not_found, vec!["Mallory".to_string()]);
+
+        let data = &replay.packets[0].data;
+        assert_eq!(data.len(), 25);
+        assert_ne!(&data[5..9], b"Jos\xe9");
+        assert_eq!(&data[16..18], b"^1");
+        assert_ne!(&data[18..20], b"Bo");
+        assert_eq!(&data[20..22], b"^7");
+        assert!(replay.value.player_names.contains("Mallory"));
+        assert!(!replay.value.player_names.contains("Bob"));
+    }
+
+    #[test]
+    fn censored_names_skip_unchanged() {
+        let mut replay = QueryReplay {
+            query: QueryOptions {
+                address: "127.0.0.1".to_string(),
+                port: Some(27960),
+                game: "q3a".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: None,
+                udp_port: Some(27960),
+                packet_size: 0,
+            },
+            packets: vec![Packet {
+                direction: PacketDirection::FromServer,
+                protocol: PacketProtocol::Udp,
+                src_port: 0,
+                dst_port: 0,
+                data: b"0 0 \"Bob\"\n0 0 \"---\"\n".to_vec(),
+            }],
+            value: CommonValue {
+                name: None,
+                map: None,
+                has_password: None,
+                players_online: Some(3),
+                players_maximum: None,
+                player_names: ["Bob", "---", "Mallory"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+        let names = replay.value.player_names.clone();
+
+        packet_name_replace(&mut replay, 1).unwrap();
+        // "---" is its own pseudonym and Mallory wasn't found, neither is a leak
+        let censored = censored_names(&names, &replay);
+        assert_eq!(censored, vec![b"Bob".to_vec()]);
+        assert!(find_leaks(&replay, &censored).is_empty());
+    }
+
+    #[test]
+    fn privacy() {
+        let packet = |direction, data: &[u8]| Packet {
+            direction,
+            protocol: PacketProtocol::Udp,
+            src_port: 0,
+            dst_port: 0,
+            data: data.to_vec(),
+        };
+        let mut replay = QueryReplay {
+            query: QueryOptions {
+                address: "203.0.113.7".to_string(),
+                port: Some(27015),
+                game: "csgo".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: None,
+                udp_port: Some(27015),
+                packet_size: 0,
+            },
+            packets: vec![
+                packet(PacketDirection::ToServer, b"connect 203.0.113.7"),
+                packet(
+                    PacketDirection::FromServer,
+                    b"\xff\xff\xff\xffI\x11My Server 203.0.113.7\0de_dust2\0csgo\0CS\0\xda\x02\x01\x0a\0dl\0\x011.0\0",
+                ),
+                packet(
+                    PacketDirection::FromServer,
+                    // The score and duration happen to contain "Alice" and are left as is
+                    b"\xff\xff\xff\xffD\x01\0Alice\0Alice\0\x80\x3f",
+                ),
+            ],
+            value: CommonValue {
+                name: Some("My Server 203.0.113.7".to_string()),
+                map: Some("de_dust2".to_string()),
+                has_password: None,
+                players_online: Some(1),
+                players_maximum: Some(10),
+                player_names: ["Alice".to_string(), "Mallory".to_string()]
+                    .into_iter()
+                    .collect(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+
+        let report = privacy_redact(
+            &mut replay,
+            &ProtocolRules::Source,
+            &PrivacyOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.names_not_found, vec!["Mallory".to_string()]);
 
-        let generated: Vec<u8> = inf.take(12).collect();
-        assert_eq!(generated, vec![0, 1, 2, 0, 0, 0, 1, 0, 2, 1, 0, 1]);
+        assert_eq!(replay.query.address, "192.0.2.1");
+        assert_eq!(replay.packets[0].data, b"connect xxxxxxxxxxx");
+        assert_eq!(
+            replay.packets[1].data,
+            b"\xff\xff\xff\xffI\x11Server\0map\0csgo\0CS\0\xda\x02\x01\x0a\0dl\0\x011.0\0"
+        );
+        assert_eq!(
+            replay.packets[2].data,
+            b"\xff\xff\xff\xffD\x01\0player2\0Alice\0\x80\x3f"
+        );
+        assert_eq!(replay.server.packet_size, replay.packets[1].data.len());
+        assert_eq!(replay.value.name.as_deref(), Some("Server"));
+        assert_eq!(replay.value.map.as_deref(), Some("map"));
+        assert!(replay.value.player_names.contains("player2"));
+        assert!(replay.value.player_names.contains("Mallory"));
     }
 }
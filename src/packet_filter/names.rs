@@ -0,0 +1,245 @@
+//! Finding player names in packets when the wire encoding differs from the value
+
+use super::{ProtocolRule, Replacement};
+
+/// Shortest truncated name prefix that will be matched
+const MIN_TRUNCATED_LEN: usize = 4;
+
+/// Find how a name is written in packet data and build a replacement for each form found. Names
+/// are tried as UTF-8, UTF-16LE, Latin-1, with Quake (`^1`), Minecraft (`§a`) or Source
+/// (`\x01`-`\x07`) color codes between characters and, if no other form is found, truncated
+/// (a prefix of the name followed by a null byte). Forms the protocol rule can't write the
+/// replacement into are skipped.
+pub fn name_replacements(
+    name: &str,
+    pseudonym: &str,
+    data: &[&[u8]],
+    rule: &dyn ProtocolRule,
+) -> Vec<Replacement> {
+    let contains = |needle: &[u8]| {
+        data.iter()
+            .any(|data| data.windows(needle.len()).any(|window| window == needle))
+    };
+
+    let mut forms: Vec<Replacement> = Vec::new();
+    let push = |forms: &mut Vec<Replacement>, form: Vec<u8>, replacement: Vec<u8>| {
+        if !form.is_empty()
+            && rule.check_replacement(&replacement).is_ok()
+            && !forms.iter().any(|(f, _)| *f == form)
+        {
+            forms.push((form, replacement));
+        }
+    };
+
+    if contains(name.as_bytes()) {
+        push(
+            &mut forms,
+            name.as_bytes().to_vec(),
+            pseudonym.as_bytes().to_vec(),
+        );
+    }
+
+    let utf16 = utf16le(name);
+    if contains(&utf16) {
+        push(&mut forms, utf16, utf16le(pseudonym));
+    }
+
+    if let Some(latin1) = latin1(name).filter(|latin1| latin1 != name.as_bytes()) {
+        if contains(&latin1) {
+            push(&mut forms, latin1, pseudonym.as_bytes().to_vec());
+        }
+    }
+
+    for data in data {
+        for form in find_with_color_codes(data, name.as_bytes()) {
+            push(&mut forms, form, pseudonym.as_bytes().to_vec());
+        }
+    }
+
+    if forms.is_empty() {
+        if let Some(prefix) = find_truncated(data, name.as_bytes()) {
+            let mut replacement = pseudonym.as_bytes().to_vec();
+            replacement.push(0);
+            push(&mut forms, prefix, replacement);
+        }
+    }
+
+    forms
+}
+
+/// Replacement for a form found by [name_replacements] that has the same length as the form, so
+/// it can be replaced in place. The pseudonym must have the same length as the name in each
+/// encoding (see [super::Pseudonyms]). Color codes are kept and truncated names are replaced by a
+/// prefix of the pseudonym. Returns [None] if the form can't be replaced in place.
+pub(super) fn same_length_replacement(form: &[u8], name: &str, pseudonym: &str) -> Option<Vec<u8>> {
+    let replacement = if form == name.as_bytes() {
+        pseudonym.as_bytes().to_vec()
+    } else if form == utf16le(name) {
+        utf16le(pseudonym)
+    } else if latin1(name).is_some_and(|latin1| form == latin1) {
+        latin1(pseudonym)?
+    } else if let Some(prefix) = form
+        .strip_suffix(&[0])
+        .filter(|prefix| name.as_bytes().starts_with(prefix))
+    {
+        let mut replacement = pseudonym.as_bytes().get(..prefix.len())?.to_vec();
+        replacement.push(0);
+        replacement
+    } else {
+        // Color codes, replace each byte of the name and keep the codes in between
+        let (name, pseudonym) = (name.as_bytes(), pseudonym.as_bytes());
+        let mut replacement = Vec::with_capacity(form.len());
+        let mut pos = 0;
+        for (byte, new_byte) in name.iter().zip(pseudonym) {
+            let next = skip_color_codes(form, pos);
+            replacement.extend_from_slice(&form[pos..next]);
+            if form.get(next) != Some(byte) {
+                return None;
+            }
+            replacement.push(*new_byte);
+            pos = next + 1;
+        }
+        replacement.extend_from_slice(&form[pos..]);
+        replacement
+    };
+
+    (replacement.len() == form.len()).then_some(replacement)
+}
+
+/// Other encodings a string may be written in, with the name of each encoding
+pub(super) fn encodings(original: &[u8]) -> Vec<(&'static str, Vec<u8>)> {
+    let mut encoded = vec![("UTF-8", original.to_vec())];
+    if let Ok(s) = std::str::from_utf8(original) {
+        encoded.push(("UTF-16LE", utf16le(s)));
+        if let Some(latin1) = latin1(s).filter(|latin1| latin1 != original) {
+            encoded.push(("Latin-1", latin1));
+        }
+    }
+    encoded
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+fn latin1(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(u32::from(c)).ok()).collect()
+}
+
+/// Skip any color codes starting at pos
+fn skip_color_codes(data: &[u8], mut pos: usize) -> usize {
+    loop {
+        match data[pos..] {
+            [b'^', digit, ..] if digit.is_ascii_digit() => pos += 2,
+            [0xc2, 0xa7, code, ..] if code.is_ascii_alphanumeric() => pos += 3,
+            [code, ..] if (0x01..=0x07).contains(&code) => pos += 1,
+            _ => return pos,
+        }
+    }
+}
+
+/// Find the name with color codes before or between its characters, only forms that contain
+/// color codes are returned
+pub(super) fn find_with_color_codes(data: &[u8], name: &[u8]) -> Vec<Vec<u8>> {
+    let mut found: Vec<Vec<u8>> = Vec::new();
+    if name.is_empty() {
+        return found;
+    }
+
+    let mut start = 0;
+    'outer: while start < data.len() {
+        let mut pos = start;
+        for byte in name {
+            pos = skip_color_codes(data, pos);
+            if data.get(pos) != Some(byte) {
+                start += 1;
+                continue 'outer;
+            }
+            pos += 1;
+        }
+
+        let form = &data[start..pos];
+        if form.len() != name.len() && !found.iter().any(|f| f == form) {
+            found.push(form.to_vec());
+        }
+        start = pos;
+    }
+    found
+}
+
+/// Find the longest prefix of the name followed by a null byte, prefixes must be at least half
+/// the name
+fn find_truncated(data: &[&[u8]], name: &[u8]) -> Option<Vec<u8>> {
+    let shortest = MIN_TRUNCATED_LEN.max(name.len() / 2);
+    (shortest..name.len()).rev().find_map(|len| {
+        let mut needle = name[..len].to_vec();
+        needle.push(0);
+        data.iter()
+            .any(|data| data.windows(needle.len()).any(|window| window == needle))
+            .then_some(needle)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{name_replacements, same_length_replacement};
+    use crate::packet_filter::ProtocolRules;
+
+    #[test]
+    fn name_encodings() {
+        let forms = |data: &[u8], name| {
+            name_replacements(name, "p1", &[data], &ProtocolRules::Raw)
+                .into_iter()
+                .map(|(form, _)| form)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(forms(b"\0Bob\0", "Bob"), vec![b"Bob".to_vec()]);
+        assert_eq!(
+            forms(b"\0B\0o\0b\0\0\0", "Bob"),
+            vec![b"B\0o\0b\0".to_vec()]
+        );
+        assert_eq!(forms(b"\0Jos\xe9\0", "José"), vec![b"Jos\xe9".to_vec()]);
+        assert_eq!(
+            forms(b"\0^1Bo^7b\0\xc2\xa7aBob\0", "Bob"),
+            vec![
+                b"Bob".to_vec(),
+                b"^1Bo^7b".to_vec(),
+                b"\xc2\xa7aBob".to_vec()
+            ]
+        );
+        assert_eq!(
+            forms(b"\0Alexand\0", "Alexander"),
+            vec![b"Alexand\0".to_vec()]
+        );
+        assert!(forms(b"\0Someone\0", "Bob").is_empty());
+    }
+
+    #[test]
+    fn same_length_forms() {
+        let replace = |form: &[u8], name| same_length_replacement(form, name, "Xyz");
+
+        assert_eq!(replace(b"Bob", "Bob"), Some(b"Xyz".to_vec()));
+        assert_eq!(replace(b"B\0o\0b\0", "Bob"), Some(b"X\0y\0z\0".to_vec()));
+        assert_eq!(replace(b"^1Bo^7b", "Bob"), Some(b"^1Xy^7z".to_vec()));
+        assert_eq!(replace(b"Bo\0", "Bob"), Some(b"Xy\0".to_vec()));
+        assert_eq!(
+            same_length_replacement(b"Jos\xe9", "Jos\u{e9}", "Abc\u{f1}"),
+            Some(b"Abc\xf1".to_vec())
+        );
+        // A pseudonym that can't be written as Latin-1
+        assert_eq!(
+            same_length_replacement(b"Jos\xe9", "Jos\u{e9}", "Abc\u{3b1}"),
+            None
+        );
+    }
+
+    #[test]
+    fn utf16_rejected_by_rule() {
+        // Source strings are null terminated so UTF-16 replacements can't be written
+        let forms = name_replacements("Bob", "p1", &[b"B\0o\0b\0"], &ProtocolRules::Source);
+        assert!(forms.is_empty());
+    }
+}
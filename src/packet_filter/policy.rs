@@ -0,0 +1,446 @@
+//! Redaction policies loaded from TOML or JSON rules files
+//!
+//! ```toml
+//! # Optional, picked from the game name by default
+//! protocol = "source"
+//! # Optional, changes every pseudonym
+//! seed = "my-project"
+//!
+//! [[rule]]
+//! field = "player_names"
+//! strategy = "pseudonym"
+//!
+//! [[rule]]
+//! literal = "My Community"
+//! strategy = "fixed"
+//! value = "Community"
+//!
+//! [[rule]]
+//! regex = "[0-9]+\\.[0-9]+\\.[0-9]+\\.[0-9]+"
+//! strategy = "zero"
+//!
+//! [[rule]]
+//! bytes = "deadbeef"
+//! strategy = "random"
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+
+use super::pseudonym::fnv1a64;
+use super::{parse_seed, redact_replay, redact_value, FilterError, ProtocolRules};
+use super::{Replacement, Replacer};
+use crate::packet::PacketDirection;
+use crate::value::CommonValue;
+use crate::{Error, QueryReplay};
+
+/// A set of redaction rules applied together
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct RedactionPolicy {
+    /// Protocol rule used to fix up length fields (see [ProtocolRules]), picked from the game
+    /// name if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+    /// Seed for pseudonyms, a number or any string (see [parse_seed])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<String>,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<RedactionRule>,
+}
+
+/// What to redact and how to replace it
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct RedactionRule {
+    #[serde(flatten)]
+    pub target: RedactionTarget,
+    #[serde(flatten)]
+    pub strategy: ReplacementStrategy,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionTarget {
+    /// A string
+    Literal(String),
+    /// Bytes written as hex
+    Bytes(String),
+    /// A regular expression matched against the raw bytes of packets and value strings
+    Regex(String),
+    /// The current contents of a value field
+    Field(ValueField),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueField {
+    Name,
+    Map,
+    PlayerNames,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum ReplacementStrategy {
+    /// Replace with a fixed string
+    Fixed { value: String },
+    /// Replace with a name derived from a hash of the original and the policy's seed, so the
+    /// same input always gets the same pseudonym
+    Pseudonym,
+    /// Replace with random letters and digits of the same length
+    Random,
+    /// Replace with the same number of "0" characters, not zero bytes as those end strings in
+    /// most protocols
+    Zero,
+}
+
+impl ReplacementStrategy {
+    fn replace(&self, original: &[u8], seed: Option<u64>, random: &RandomState) -> Vec<u8> {
+        match self {
+            ReplacementStrategy::Fixed { value } => value.as_bytes().to_vec(),
+            ReplacementStrategy::Pseudonym => {
+                let mut key = Vec::with_capacity(8 + original.len());
+                if let Some(seed) = seed {
+                    key.extend_from_slice(&seed.to_le_bytes());
+                }
+                key.extend_from_slice(original);
+                format!("anon-{:08x}", fnv1a64(&key) as u32).into_bytes()
+            }
+            ReplacementStrategy::Random => {
+                const CHARS: &[u8] =
+                    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+                (0..original.len())
+                    .map(|i| {
+                        let mut hasher = random.build_hasher();
+                        hasher.write(original);
+                        hasher.write_usize(i);
+                        CHARS[(hasher.finish() % CHARS.len() as u64) as usize]
+                    })
+                    .collect()
+            }
+            ReplacementStrategy::Zero => vec![b'0'; original.len()],
+        }
+    }
+}
+
+impl RedactionPolicy {
+    /// Load a policy, files ending in `.toml` are parsed as TOML (requires the toml feature),
+    /// anything else as JSON
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            Self::from_toml(&content)
+        } else {
+            Ok(serde_json::from_str(&content)?)
+        }
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml(content: &str) -> Result<Self, Error> {
+        toml::from_str(content).map_err(|e| Error::String(format!("Invalid rules file: {}", e)))
+    }
+
+    #[cfg(not(feature = "toml"))]
+    pub fn from_toml(_content: &str) -> Result<Self, Error> {
+        Err(Error::String(
+            "TOML rules files require the toml feature".to_string(),
+        ))
+    }
+
+    /// The protocol rule to use for a replay
+    pub fn protocol_rule(&self, query_replay: &QueryReplay) -> Result<ProtocolRules, Error> {
+        match &self.protocol {
+            Some(protocol) => protocol.parse().map_err(Error::String),
+            None => Ok(ProtocolRules::for_game(&query_replay.query.game)),
+        }
+    }
+
+    /// Work out the concrete replacements for a replay, each distinct match is replaced the same
+    /// way everywhere. When several rules match the same string the earlier rule's replacement is
+    /// used, where different matches overlap the longest is replaced (see [super::Replacer]).
+    pub fn replacements(&self, query_replay: &QueryReplay) -> Result<Vec<Replacement>, Error> {
+        let random = RandomState::new();
+        let seed = self.seed.as_deref().map(parse_seed);
+        let mut replacements: Vec<Replacement> = Vec::new();
+        // Pseudonyms are truncated hashes, two originals sharing one would be indistinguishable
+        let mut pseudonyms = HashSet::new();
+
+        for rule in &self.rules {
+            for original in rule.target.matches(query_replay)? {
+                if original.is_empty() || replacements.iter().any(|(o, _)| *o == original) {
+                    continue;
+                }
+                let replacement = rule.strategy.replace(&original, seed, &random);
+                if matches!(rule.strategy, ReplacementStrategy::Pseudonym)
+                    && !pseudonyms.insert(replacement.clone())
+                {
+                    return Err(FilterError::PseudonymCollision.into());
+                }
+                replacements.push((original, replacement));
+            }
+        }
+
+        // Longest first so strings containing other strings are replaced whole
+        replacements.sort_by_key(|(original, _)| std::cmp::Reverse(original.len()));
+        Ok(replacements)
+    }
+
+    /// Redact a replay's packets from the server and its expected value, returning the number of
+    /// replacements made in packets
+    pub fn apply(&self, query_replay: &mut QueryReplay) -> Result<usize, Error> {
+        let rule = self.protocol_rule(query_replay)?;
+        // One replacer for both the packets and the value
+        let replacer = Replacer::new(&self.replacements(query_replay)?)?;
+
+        let counts = redact_replay(query_replay, &replacer, &rule)?;
+        redact_value(&mut query_replay.value, &replacer);
+
+        Ok(counts.into_iter().sum())
+    }
+}
+
+impl RedactionTarget {
+    /// Every distinct string this target matches in a replay
+    fn matches(&self, query_replay: &QueryReplay) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(match self {
+            RedactionTarget::Literal(literal) => vec![literal.as_bytes().to_vec()],
+            RedactionTarget::Bytes(hex) => vec![parse_hex(hex)?],
+            RedactionTarget::Field(field) => field_values(&query_replay.value, *field),
+            RedactionTarget::Regex(pattern) => {
+                let regex = regex::bytes::Regex::new(pattern)
+                    .map_err(|e| Error::String(format!("Invalid regex {:?}: {}", pattern, e)))?;
+
+                let packets = query_replay
+                    .packets
+                    .iter()
+                    .filter(|packet| packet.direction == PacketDirection::FromServer)
+                    .map(|packet| packet.data.clone());
+                let values = [ValueField::Name, ValueField::Map, ValueField::PlayerNames]
+                    .into_iter()
+                    .flat_map(|field| field_values(&query_replay.value, field));
+
+                let mut found: Vec<Vec<u8>> = Vec::new();
+                for data in packets.chain(values) {
+                    for m in regex.find_iter(&data) {
+                        if !found.iter().any(|f| f == m.as_bytes()) {
+                            found.push(m.as_bytes().to_vec());
+                        }
+                    }
+                }
+                found
+            }
+        })
+    }
+}
+
+fn field_values(value: &CommonValue, field: ValueField) -> Vec<Vec<u8>> {
+    match field {
+        ValueField::Name => value.name.iter().map(|s| s.as_bytes().to_vec()).collect(),
+        ValueField::Map => value.map.iter().map(|s| s.as_bytes().to_vec()).collect(),
+        ValueField::PlayerNames => {
+            let mut names: Vec<_> = value
+                .player_names
+                .iter()
+                .map(|s| s.as_bytes().to_vec())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+}
+
+/// Parse hex bytes, whitespace between bytes is ignored
+fn parse_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::String(format!("Invalid hex bytes {:?}", hex));
+    let digits: Vec<u8> = hex
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    if digits.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FilterError, RedactionPolicy, RedactionTarget, ReplacementStrategy, ValueField};
+    use crate::options::{QueryOptions, ServerOptions};
+    use crate::packet::{Packet, PacketDirection, PacketProtocol};
+    use crate::value::CommonValue;
+    use crate::{QueryReplay, REPLAY_VERSION};
+
+    #[test]
+    fn parse_policy() {
+        let policy: RedactionPolicy = serde_json::from_str(
+            r#"{
+                "protocol": "source",
+                "rule": [
+                    { "field": "player_names", "strategy": "pseudonym" },
+                    { "literal": "My Server", "strategy": "fixed", "value": "Server" },
+                    { "bytes": "dead beef", "strategy": "zero" },
+                    { "regex": "[0-9]+", "strategy": "random" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(policy.rules.len(), 4);
+        assert!(matches!(
+            policy.rules[0].target,
+            RedactionTarget::Field(ValueField::PlayerNames)
+        ));
+        assert!(
+            matches!(&policy.rules[1].strategy, ReplacementStrategy::Fixed { value } if value == "Server")
+        );
+        assert!(matches!(
+            policy.rules[2].strategy,
+            ReplacementStrategy::Zero
+        ));
+        assert!(matches!(
+            policy.rules[3].strategy,
+            ReplacementStrategy::Random
+        ));
+    }
+
+    #[test]
+    fn apply_policy() {
+        let packet = |direction, data: &[u8]| Packet {
+            direction,
+            protocol: PacketProtocol::Udp,
+            src_port: 0,
+            dst_port: 0,
+            data: data.to_vec(),
+        };
+        let mut replay = QueryReplay {
+            query: QueryOptions {
+                address: "203.0.113.7".to_string(),
+                port: Some(27015),
+                game: "csgo".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: None,
+                udp_port: Some(27015),
+                packet_size: 0,
+            },
+            packets: vec![
+                packet(PacketDirection::ToServer, b"\xff\xff\xff\xffTSource Engine Query\0"),
+                packet(
+                    PacketDirection::FromServer,
+                    b"\xff\xff\xff\xffI\x11My Server 10.0.0.1\0de_dust2\0csgo\0CS\0\xda\x02\x01\x0a\0dl\0\x011.0\0",
+                ),
+                packet(
+                    PacketDirection::FromServer,
+                    b"\xff\xff\xff\xffD\x01\0Alice\0\0\0\0\0\0\0\x80\x3f",
+                ),
+            ],
+            value: CommonValue {
+                name: Some("My Server 10.0.0.1".to_string()),
+                map: Some("de_dust2".to_string()),
+                has_password: None,
+                players_online: Some(1),
+                players_maximum: Some(10),
+                player_names: ["Alice".to_string()].into_iter().collect(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+
+        let policy: RedactionPolicy = serde_json::from_str(
+            r#"{
+                "seed": "test",
+                "rule": [
+                    { "field": "player_names", "strategy": "pseudonym" },
+                    { "literal": "My Server", "strategy": "fixed", "value": "Server" },
+                    { "regex": "[0-9]+\\.[0-9]+\\.[0-9]+\\.[0-9]+", "strategy": "zero" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(policy.apply(&mut replay).unwrap(), 3);
+
+        let pseudonym = replay.value.player_names.iter().next().unwrap().clone();
+        assert!(pseudonym.starts_with("anon-"));
+        assert_eq!(replay.value.name.as_deref(), Some("Server 00000000"));
+        assert_eq!(
+            replay.packets[1].data,
+            b"\xff\xff\xff\xffI\x11Server 00000000\0de_dust2\0csgo\0CS\0\xda\x02\x01\x0a\0dl\0\x011.0\0"
+        );
+        let mut players = b"\xff\xff\xff\xffD\x01\0".to_vec();
+        players.extend_from_slice(pseudonym.as_bytes());
+        players.extend_from_slice(b"\0\0\0\0\0\0\0\x80\x3f");
+        assert_eq!(replay.packets[2].data, players);
+    }
+
+    #[test]
+    fn pseudonym_collision() {
+        let replay = QueryReplay {
+            query: QueryOptions {
+                address: "203.0.113.7".to_string(),
+                port: Some(27015),
+                game: "csgo".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: None,
+                udp_port: Some(27015),
+                packet_size: 0,
+            },
+            packets: Vec::new(),
+            value: CommonValue {
+                name: None,
+                map: None,
+                has_password: None,
+                players_online: Some(2),
+                players_maximum: None,
+                // Both hash to anon-67579ad5 without a seed
+                player_names: ["player224191", "player816080"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+
+        let policy: RedactionPolicy = serde_json::from_str(
+            r#"{ "rule": [{ "field": "player_names", "strategy": "pseudonym" }] }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            policy.replacements(&replay),
+            Err(crate::Error::Filter(FilterError::PseudonymCollision))
+        ));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn parse_toml_policy() {
+        let policy = RedactionPolicy::from_toml(
+            "protocol = \"minecraft\"\n\
+             [[rule]]\n\
+             regex = \"[0-9]+\"\n\
+             strategy = \"fixed\"\n\
+             value = \"0\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(policy.protocol.as_deref(), Some("minecraft"));
+        assert!(
+            matches!(&policy.rules[0].target, RedactionTarget::Regex(regex) if regex == "[0-9]+")
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Deterministic pseudonyms for player names
+
+use std::collections::HashSet;
+
+/// Seed used when none is given. The hash isn't keyed by a secret then, so anyone can reverse a
+/// pseudonym by generating pseudonyms for likely names. Use a seed that is kept private to prevent
+/// that.
+pub const DEFAULT_PSEUDONYM_SEED: u64 = 0;
+
+/// Attempts at finding an unused pseudonym before giving up (e.g. for a one digit name there are
+/// only 10 pseudonyms)
+const MAX_ATTEMPTS: u32 = 64;
+
+/// Generates pseudonyms from a hash of the original name and a seed, so the same name always gets
+/// the same pseudonym for a seed. Pseudonyms have the same length in both UTF-8 and UTF-16 as the
+/// original and keep the class of each character: lowercase and uppercase ASCII letters and digits
+/// are replaced by another of the same class, other ASCII is kept, Latin-1 characters are replaced
+/// by a Latin-1 letter (so names written as Latin-1 can be replaced in place) and other characters
+/// are replaced by a character of the same encoded length. The hash isn't cryptographic, a
+/// pseudonym only hides the name from someone who doesn't know the seed (see
+/// [DEFAULT_PSEUDONYM_SEED]).
+#[derive(Debug, Clone)]
+pub struct Pseudonyms {
+    seed: u64,
+    used: HashSet<String>,
+}
+
+impl Pseudonyms {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            used: HashSet::new(),
+        }
+    }
+
+    /// Stop a pseudonym from being generated, e.g. because it is another player's name
+    pub fn reserve(&mut self, name: &str) {
+        self.used.insert(name.to_string());
+    }
+
+    /// Generate a pseudonym that hasn't been generated or reserved before and differs from the
+    /// original, names with only ASCII punctuation are kept as is. Returns [None] if every
+    /// pseudonym tried was already used. Names should be generated in a fixed order (e.g. sorted)
+    /// for collisions to be resolved the same way each time.
+    pub fn pseudonym(&mut self, original: &str) -> Option<String> {
+        if original
+            .chars()
+            .all(|c| c.is_ascii() && !c.is_ascii_alphanumeric())
+        {
+            return Some(original.to_string());
+        }
+
+        let pseudonym = (0..MAX_ATTEMPTS)
+            .map(|attempt| self.generate(original, attempt))
+            .find(|pseudonym| pseudonym != original && !self.used.contains(pseudonym))?;
+        self.used.insert(pseudonym.clone());
+        Some(pseudonym)
+    }
+
+    fn generate(&self, original: &str, attempt: u32) -> String {
+        let mut key = self.seed.to_le_bytes().to_vec();
+        key.extend_from_slice(original.as_bytes());
+        key.extend_from_slice(&attempt.to_le_bytes());
+
+        original
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                let mut key = key.clone();
+                key.extend_from_slice(&(i as u32).to_le_bytes());
+                let hash = fnv1a64(&key);
+
+                let (start, count) = match c {
+                    'a'..='z' => ('a' as u32, 26),
+                    'A'..='Z' => ('A' as u32, 26),
+                    '0'..='9' => ('0' as u32, 10),
+                    c if c.is_ascii() => return c,
+                    // Latin-1 lowercase letters (à to ö)
+                    '\u{80}'..='\u{ff}' => (0xe0, 23),
+                    // Greek lowercase
+                    c if c.len_utf8() == 2 => (0x3b1, 25),
+                    // CJK unified ideographs
+                    c if c.len_utf8() == 3 => (0x4e00, 0x5000),
+                    // CJK extension B, two UTF-16 code units like the original
+                    _ => (0x20000, 0xa6d0),
+                };
+                char::from_u32(start + (hash % count) as u32).unwrap_or(c)
+            })
+            .collect()
+    }
+}
+
+/// Parse a seed, numbers are used as is and anything else (e.g. a project name) is hashed
+pub fn parse_seed(seed: &str) -> u64 {
+    seed.parse().unwrap_or_else(|_| fnv1a64(seed.as_bytes()))
+}
+
+pub(crate) fn fnv1a64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pseudonyms;
+
+    #[test]
+    fn stable_pseudonyms() {
+        let generate = |seed, names: &[&str]| {
+            let mut pseudonyms = Pseudonyms::new(seed);
+            names
+                .iter()
+                .map(|name| pseudonyms.pseudonym(name).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let names = ["Alice_99", "bob", "Zoë", "名前"];
+        let first = generate(1, &names);
+        assert_eq!(first, generate(1, &names));
+        assert_ne!(first, generate(2, &names));
+
+        for (name, pseudonym) in names.iter().zip(&first) {
+            assert_ne!(name, pseudonym);
+            assert_eq!(name.len(), pseudonym.len());
+            assert_eq!(
+                name.encode_utf16().count(),
+                pseudonym.encode_utf16().count()
+            );
+            for (a, b) in name.chars().zip(pseudonym.chars()) {
+                assert_eq!(a.is_ascii_lowercase(), b.is_ascii_lowercase());
+                assert_eq!(a.is_ascii_uppercase(), b.is_ascii_uppercase());
+                assert_eq!(a.is_ascii_digit(), b.is_ascii_digit());
+            }
+        }
+        assert_eq!(first[0].as_bytes()[5], b'_');
+    }
+
+    #[test]
+    fn no_collisions() {
+        let mut pseudonyms = Pseudonyms::new(0);
+        let generated: std::collections::HashSet<_> = (0..10)
+            .map(|i| pseudonyms.pseudonym(&i.to_string()).unwrap())
+            .collect();
+        assert_eq!(generated.len(), 10);
+
+        // Every one digit pseudonym is used
+        assert_eq!(pseudonyms.pseudonym("5"), None);
+        assert_eq!(pseudonyms.pseudonym("--").as_deref(), Some("--"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Replacing many patterns in a single pass
+
+use aho_corasick::{AhoCorasick, MatchKind};
+
+use super::{FilterError, Replacement};
+
+/// Replaces every pattern in a single pass over a buffer using an Aho–Corasick automaton. Where
+/// matches overlap the leftmost wins, and of matches starting at the same position the longest
+/// wins. Matches never overlap so each byte is replaced at most once.
+#[derive(Debug, Clone)]
+pub struct Replacer {
+    matcher: AhoCorasick,
+    replacements: Vec<Replacement>,
+}
+
+impl Replacer {
+    /// Build a replacer, patterns must not be empty
+    pub fn new(replacements: &[Replacement]) -> Result<Self, FilterError> {
+        if replacements.iter().any(|(pattern, _)| pattern.is_empty()) {
+            return Err(FilterError::EmptyReplace);
+        }
+
+        let matcher = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(replacements.iter().map(|(pattern, _)| pattern))
+            // Only happens if the automaton is too large to build
+            .map_err(|_| FilterError::Unsupported)?;
+
+        Ok(Self {
+            matcher,
+            replacements: replacements.to_vec(),
+        })
+    }
+
+    /// The patterns and what they're replaced with
+    pub fn replacements(&self) -> &[Replacement] {
+        &self.replacements
+    }
+
+    /// Number of patterns
+    pub fn len(&self) -> usize {
+        self.replacements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replacements.is_empty()
+    }
+
+    /// Replace into a new buffer, replacements may be a different length. Returns the new buffer
+    /// and how many times each pattern was replaced.
+    pub fn replace(&self, buffer: &[u8]) -> (Vec<u8>, Vec<usize>) {
+        let mut counts = vec![0; self.len()];
+        let mut output = Vec::with_capacity(buffer.len());
+
+        let mut pos = 0;
+        for found in self.matcher.find_iter(buffer) {
+            let pattern = found.pattern().as_usize();
+            output.extend_from_slice(&buffer[pos..found.start()]);
+            output.extend_from_slice(&self.replacements[pattern].1);
+            counts[pattern] += 1;
+            pos = found.end();
+        }
+        output.extend_from_slice(&buffer[pos..]);
+
+        (output, counts)
+    }
+
+    /// Replace in place, every replacement must be the same length as its pattern. Returns how
+    /// many times each pattern was replaced.
+    pub fn replace_in_place(&self, buffer: &mut [u8]) -> Result<Vec<usize>, FilterError> {
+        if self
+            .replacements
+            .iter()
+            .any(|(pattern, replacement)| pattern.len() != replacement.len())
+        {
+            return Err(FilterError::MismatchReplaceLen);
+        }
+
+        let mut counts = vec![0; self.len()];
+        let found: Vec<_> = self.matcher.find_iter(&*buffer).collect();
+        for found in found {
+            let pattern = found.pattern().as_usize();
+            buffer[found.range()].copy_from_slice(&self.replacements[pattern].1);
+            counts[pattern] += 1;
+        }
+
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::Replacer;
+    use crate::packet_filter::{naive_replace, raw_replace, Replacement};
+
+    /// Sort patterns longest first so the naive first-in-list-wins replacement is leftmost-longest
+    fn sorted(mut replacements: Vec<Replacement>) -> Vec<Replacement> {
+        replacements.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(&b.0)));
+        replacements.dedup_by(|a, b| a.0 == b.0);
+        replacements
+    }
+
+    #[test]
+    fn leftmost_longest() {
+        let replacer = Replacer::new(&[
+            (b"Bob".to_vec(), b"p1".to_vec()),
+            (b"Bobby".to_vec(), b"p2".to_vec()),
+            (b"by".to_vec(), b"p3".to_vec()),
+        ])
+        .unwrap();
+
+        let (replaced, counts) = replacer.replace(b"Bobby Bob by");
+        assert_eq!(replaced, b"p2 p1 p3");
+        assert_eq!(counts, vec![1, 1, 1]);
+
+        let mut buffer = b"aaaa".to_vec();
+        let replacer = Replacer::new(&[(b"aaa".to_vec(), b"bbb".to_vec())]).unwrap();
+        assert_eq!(replacer.replace_in_place(&mut buffer).unwrap(), vec![1]);
+        assert_eq!(buffer, b"bbba");
+
+        assert!(Replacer::new(&[(Vec::new(), b"x".to_vec())]).is_err());
+        assert!(Replacer::new(&[(b"ab".to_vec(), b"x".to_vec())])
+            .unwrap()
+            .replace_in_place(&mut buffer)
+            .is_err());
+    }
+
+    proptest! {
+        // A small alphabet so that patterns match and overlap often
+        #[test]
+        fn replace_matches_naive(
+            buffer in vec(0u8..4, 0..256),
+            replacements in vec((vec(0u8..4, 1..6), vec(any::<u8>(), 0..6)), 1..12),
+        ) {
+            let replacements = sorted(replacements);
+            let replacer = Replacer::new(&replacements).unwrap();
+            prop_assert_eq!(replacer.replace(&buffer), naive_replace(&buffer, &replacements));
+        }
+
+        #[test]
+        fn replace_in_place_matches_naive(
+            buffer in vec(0u8..4, 0..256),
+            patterns in vec(vec(0u8..4, 1..6), 1..12),
+        ) {
+            let replacements = sorted(
+                patterns
+                    .into_iter()
+                    .map(|pattern| (pattern.clone(), vec![0xff; pattern.len()]))
+                    .collect(),
+            );
+            let replacer = Replacer::new(&replacements).unwrap();
+
+            let mut in_place = buffer.clone();
+            let counts = replacer.replace_in_place(&mut in_place).unwrap();
+            prop_assert_eq!((in_place, counts), naive_replace(&buffer, &replacements));
+        }
+
+        #[test]
+        fn raw_replace_matches_naive(
+            buffer in vec(0u8..4, 0..256),
+            pattern in vec(0u8..4, 1..6),
+        ) {
+            let replacements = vec![(pattern.clone(), vec![0xff; pattern.len()])];
+
+            let mut replaced = buffer.clone();
+            let count = raw_replace(&mut replaced, &pattern, &replacements[0].1).unwrap();
+            prop_assert_eq!((replaced, vec![count]), naive_replace(&buffer, &replacements));
+        }
+    }
+}
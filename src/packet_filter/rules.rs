@@ -0,0 +1,619 @@
+//! Per-protocol rules for redacting packets when replacements change the payload length
+
+use super::FilterError;
+
+/// Rewrites a message from the server so that replacements can change its length, the rule
+/// decides which parts of the message may be replaced and re-encodes any length fields
+pub trait ProtocolRule {
+    /// Check a replacement can be written into this protocol (e.g. doesn't contain a delimiter)
+    fn check_replacement(&self, replacement: &[u8]) -> Result<(), FilterError>;
+
+    /// Rewrite a message by calling `replace` on its content and fixing up any length fields,
+    /// messages are a single UDP packet or a run of TCP packets in the same direction
+    fn rewrite(
+        &self,
+        message: &[u8],
+        replace: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+    ) -> Result<Vec<u8>, FilterError>;
+
+    /// ID of the message a UDP packet is part of when the server splits messages over several
+    /// packets, consecutive packets with the same ID are rewritten together with
+    /// [ProtocolRule::rewrite_split]
+    fn split_id(&self, _packet: &[u8]) -> Option<u32> {
+        None
+    }
+
+    /// Rewrite a message split over several UDP packets (see [ProtocolRule::split_id]), returning
+    /// the new packets which may be a different number
+    fn rewrite_split(
+        &self,
+        packets: &[&[u8]],
+        replace: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+    ) -> Result<Vec<Vec<u8>>, FilterError> {
+        packets
+            .iter()
+            .map(|packet| self.rewrite(packet, replace))
+            .collect()
+    }
+}
+
+/// Built in protocol rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolRules {
+    /// No length fields, replace anywhere in the message
+    Raw,
+    /// Valve source query, only the strings of A2S_INFO (including the obsolete GoldSrc
+    /// response), A2S_PLAYER and A2S_RULES responses are replaced. Uncompressed split responses
+    /// are reassembled and split again.
+    Source,
+    /// Minecraft java status, a varint length prefixed packet containing a varint length
+    /// prefixed JSON string
+    Minecraft,
+    /// GameSpy 1-3, strings are backslash or null delimited, the header of GameSpy 2 and 3
+    /// responses is kept as is
+    GameSpy,
+}
+
+impl ProtocolRules {
+    pub const ALL: [ProtocolRules; 4] = [
+        ProtocolRules::Raw,
+        ProtocolRules::Source,
+        ProtocolRules::Minecraft,
+        ProtocolRules::GameSpy,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProtocolRules::Raw => "raw",
+            ProtocolRules::Source => "source",
+            ProtocolRules::Minecraft => "minecraft",
+            ProtocolRules::GameSpy => "gamespy",
+        }
+    }
+
+    /// Guess the rule for a game ID, games that aren't known use [ProtocolRules::Source] as it is
+    /// the most common protocol
+    pub fn for_game(game: &str) -> Self {
+        let game = game.to_ascii_lowercase();
+        if MINECRAFT_GAMES.contains(&game.as_str()) {
+            ProtocolRules::Minecraft
+        } else if GAMESPY_GAMES.contains(&game.as_str()) {
+            ProtocolRules::GameSpy
+        } else {
+            ProtocolRules::Source
+        }
+    }
+}
+
+/// Game IDs queried with the Minecraft java status protocol
+const MINECRAFT_GAMES: &[&str] = &["minecraft"];
+
+/// Game IDs queried with a GameSpy protocol
+const GAMESPY_GAMES: &[&str] = &[
+    "gamespy1",
+    "gamespy2",
+    "gamespy3",
+    "protocol-gamespy1",
+    "protocol-gamespy2",
+    "protocol-gamespy3",
+    "bf1942",
+    "bf2",
+    "bf2142",
+    "crysis",
+    "crysiswars",
+    "halo",
+    "ut",
+    "ut3",
+];
+
+impl std::fmt::Display for ProtocolRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for ProtocolRules {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ProtocolRules::ALL
+            .into_iter()
+            .find(|rule| rule.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown protocol rule {:?}", s))
+    }
+}
+
+impl ProtocolRule for ProtocolRules {
+    fn check_replacement(&self, replacement: &[u8]) -> Result<(), FilterError> {
+        let forbidden: &[u8] = match self {
+            ProtocolRules::Raw => &[],
+            ProtocolRules::Source => &[0],
+            // Replacements are written inside a JSON string
+            ProtocolRules::Minecraft => b"\"\\",
+            ProtocolRules::GameSpy => &[0, b'\\'],
+        };
+
+        if replacement.iter().any(|byte| forbidden.contains(byte)) {
+            Err(FilterError::InvalidReplacement)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn rewrite(
+        &self,
+        message: &[u8],
+        replace: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+    ) -> Result<Vec<u8>, FilterError> {
+        match self {
+            ProtocolRules::Raw => Ok(replace(message)),
+            ProtocolRules::Source => rewrite_source(message, replace),
+            ProtocolRules::Minecraft => rewrite_minecraft(message, replace),
+            ProtocolRules::GameSpy => Ok(rewrite_gamespy(message, replace)),
+        }
+    }
+
+    fn split_id(&self, packet: &[u8]) -> Option<u32> {
+        match self {
+            ProtocolRules::Source if packet.len() >= SOURCE_SPLIT_HEADER_LEN => {
+                let (prefix, id) = (&packet[..4], &packet[4..8]);
+                (prefix == SOURCE_SPLIT).then(|| u32::from_le_bytes(id.try_into().unwrap()))
+            }
+            _ => None,
+        }
+    }
+
+    fn rewrite_split(
+        &self,
+        packets: &[&[u8]],
+        replace: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+    ) -> Result<Vec<Vec<u8>>, FilterError> {
+        match self {
+            ProtocolRules::Source => rewrite_source_split(packets, replace),
+            _ => packets
+                .iter()
+                .map(|packet| self.rewrite(packet, replace))
+                .collect(),
+        }
+    }
+}
+
+const SOURCE_SINGLE: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+const SOURCE_SPLIT: [u8; 4] = [0xfe, 0xff, 0xff, 0xff];
+/// Prefix, ID, total, number and maximum packet size
+const SOURCE_SPLIT_HEADER_LEN: usize = 12;
+
+/// Copies a message while replacing inside its null terminated strings
+struct StringFields<'a, 'r> {
+    data: &'a [u8],
+    pos: usize,
+    output: Vec<u8>,
+    replace: &'r mut dyn FnMut(&[u8]) -> Vec<u8>,
+}
+
+impl<'a, 'r> StringFields<'a, 'r> {
+    fn new(data: &'a [u8], replace: &'r mut dyn FnMut(&[u8]) -> Vec<u8>) -> Self {
+        Self {
+            data,
+            pos: 0,
+            output: Vec::with_capacity(data.len()),
+            replace,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    /// Copy a fixed size field as is
+    fn copy(&mut self, len: usize) -> Result<&'a [u8], FilterError> {
+        let field = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(FilterError::MalformedPacket)?;
+        self.output.extend_from_slice(field);
+        self.pos += len;
+        Ok(field)
+    }
+
+    fn byte(&mut self) -> Result<u8, FilterError> {
+        Ok(self.copy(1)?[0])
+    }
+
+    fn short(&mut self) -> Result<u16, FilterError> {
+        let field = self.copy(2)?;
+        Ok(u16::from_le_bytes([field[0], field[1]]))
+    }
+
+    /// Replace inside a null terminated string
+    fn string(&mut self) -> Result<(), FilterError> {
+        let len = self.data[self.pos..]
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(FilterError::MalformedPacket)?;
+        let replaced = (self.replace)(&self.data[self.pos..self.pos + len]);
+        self.output.extend(replaced);
+        self.output.push(0);
+        self.pos += len + 1;
+        Ok(())
+    }
+
+    /// Copy anything left as is
+    fn finish(mut self) -> Vec<u8> {
+        self.output.extend_from_slice(&self.data[self.pos..]);
+        self.output
+    }
+}
+
+fn rewrite_source(
+    message: &[u8],
+    replace: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+) -> Result<Vec<u8>, FilterError> {
+    if message.starts_with(&SOURCE_SPLIT) {
+        return rewrite_source_split(&[message], replace).map(|mut packets| packets.remove(0));
+    }
+    if !message.starts_with(&SOURCE_SINGLE) {
+        return Err(FilterError::Unsupported);
+    }
+
+    let mut fields = StringFields::new(message, replace);
+    fields.copy(SOURCE_SINGLE.len())?;
+    match fields.byte()? {
+        // A2S_INFO
+        b'I' => {
+            fields.byte()?; // Protocol
+            for _ in 0..4 {
+                fields.string()?; // Name, map, folder and game
+            }
+            let id = fields.short()?;
+            // Players, max players, bots, server type, environment, visibility and VAC
+            fields.copy(7)?;
+            if id == 2400 {
+                // The Ship's mode, witnesses and duration
+                fields.copy(3)?;
+            }
+            fields.string()?; // Version
+            if fields.remaining() > 0 {
+                let flags = fields.byte()?;
+                if flags & 0x80 != 0 {
+                    fields.copy(2)?; // Port
+                }
+                if flags & 0x10 != 0 {
+                    fields.copy(8)?; // Steam ID
+                }
+                if flags & 0x40 != 0 {
+                    fields.copy(2)?; // SourceTV port
+                    fields.string()?; // SourceTV name
+                }
+                if flags & 0x20 != 0 {
+                    fields.string()?; // Keywords
+                }
+                if flags & 0x01 != 0 {
+                    fields.copy(8)?; // Game ID
+                }
+            }
+        }
+        // Obsolete GoldSrc A2S_INFO
+        b'm' => {
+            for _ in 0..5 {
+                fields.string()?; // Address, name, map, folder and game
+            }
+            // Players, max players, protocol, server type, environment and visibility
+            fields.copy(6)?;
+            if fields.byte()? == 1 {
+                fields.string()?; // Mod link
+                fields.string()?; // Mod download link
+                fields.copy(11)?; // Null, version, size, type and DLL
+            }
+            fields.copy(2)?; // VAC and bots
+        }
+        // A2S_PLAYER
+        b'D' => {
+            for _ in 0..fields.byte()? {
+                fields.byte()?; // Index
+                fields.string()?; // Name
+                fields.copy(8)?; // Score and duration
+            }
+        }
+        // A2S_RULES
+        b'E' => {
+            for _ in 0..fields.short()? {
+                fields.string()?; // Name
+                fields.string()?; // Value
+            }
+        }
+        // Challenge
+        b'A' => {}
+        _ => return Err(FilterError::Unsupported),
+    }
+    Ok(fields.finish())
+}
+
+/// Reassemble a split response, rewrite it and split it again into packets no larger than the
+/// original packets
+fn rewrite_source_split(
+    packets: &[&[u8]],
+    replace: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+) -> Result<Vec<Vec<u8>>, FilterError> {
+    let mut parts = Vec::with_capacity(packets.len());
+    for packet in packets {
+        let header = packet
+            .get(..SOURCE_SPLIT_HEADER_LEN)
+            .ok_or(FilterError::MalformedPacket)?;
+        // Compressed responses contain a checksum and size of the whole decompressed payload
+        if header[7] & 0x80 != 0 {
+            return Err(FilterError::Unsupported);
+        }
+        let (total, number) = (header[8], header[9]);
+        if usize::from(total) != packets.len() {
+            return Err(FilterError::MalformedPacket);
+        }
+        parts.push((number, header, &packet[SOURCE_SPLIT_HEADER_LEN..]));
+    }
+    parts.sort_by_key(|(number, _, _)| *number);
+    if parts
+        .iter()
+        .enumerate()
+        .any(|(i, (number, _, _))| usize::from(*number) != i)
+    {
+        return Err(FilterError::MalformedPacket);
+    }
+
+    let payload: Vec<u8> = parts
+        .iter()
+        .flat_map(|(_, _, payload)| payload.iter().copied())
+        .collect();
+    let payload = rewrite_source(&payload, replace)?;
+
+    let part_size = parts
+        .iter()
+        .map(|(_, _, payload)| payload.len())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let chunks: Vec<&[u8]> = payload.chunks(part_size).collect();
+    let total = u8::try_from(chunks.len()).map_err(|_| FilterError::Unsupported)?;
+
+    let header = parts[0].1;
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(number, chunk)| {
+            let mut packet = header[..8].to_vec();
+            packet.push(total);
+            packet.push(number as u8);
+            packet.extend_from_slice(&header[10..]);
+            packet.extend_from_slice(chunk);
+            packet
+        })
+        .collect())
+}
+
+/// Replace inside each backslash or null delimited string, keeping the header of GameSpy 2 and
+/// 3 responses (type, session ID and the split packet number)
+fn rewrite_gamespy(message: &[u8], replace: &mut dyn FnMut(&[u8]) -> Vec<u8>) -> Vec<u8> {
+    let mut header_len = 0;
+    if message.first() == Some(&0) {
+        header_len = message.len().min(5);
+        if message[header_len..].starts_with(b"splitnum\0") {
+            header_len = message.len().min(header_len + b"splitnum\0".len() + 1);
+        }
+    }
+
+    let mut rewritten = message[..header_len].to_vec();
+    for field in message[header_len..].split_inclusive(|byte| *byte == 0 || *byte == b'\\') {
+        let (content, delimiter) = match field.split_last() {
+            Some((last, content)) if *last == 0 || *last == b'\\' => (content, Some(*last)),
+            _ => (field, None),
+        };
+        rewritten.extend(replace(content));
+        rewritten.extend(delimiter);
+    }
+    rewritten
+}
+
+fn rewrite_minecraft(
+    message: &[u8],
+    replace: &mut dyn FnMut(&[u8]) -> Vec<u8>,
+) -> Result<Vec<u8>, FilterError> {
+    let mut rewritten = Vec::with_capacity(message.len());
+    let mut pos = 0;
+
+    while pos < message.len() {
+        let (length, length_size) = read_varint(&message[pos..])?;
+        let body_start = pos + length_size;
+        let body_end = body_start + length as usize;
+        let body = message
+            .get(body_start..body_end)
+            .ok_or(FilterError::MalformedPacket)?;
+
+        let (packet_id, id_size) = read_varint(body)?;
+        if packet_id != 0 {
+            // Only the status response contains strings, copy anything else (e.g. pong)
+            rewritten.extend_from_slice(&message[pos..body_end]);
+            pos = body_end;
+            continue;
+        }
+
+        let (json_length, json_length_size) = read_varint(&body[id_size..])?;
+        let json_start = id_size + json_length_size;
+        let json = body
+            .get(json_start..json_start + json_length as usize)
+            .ok_or(FilterError::MalformedPacket)?;
+
+        let json = replace(json);
+        let mut new_body = body[..id_size].to_vec();
+        write_varint(&mut new_body, json.len() as u32);
+        new_body.extend(json);
+        new_body.extend_from_slice(&body[json_start + json_length as usize..]);
+
+        write_varint(&mut rewritten, new_body.len() as u32);
+        rewritten.extend(new_body);
+        pos = body_end;
+    }
+
+    Ok(rewritten)
+}
+
+/// Read a minecraft varint, returning the value and the number of bytes used
+fn read_varint(data: &[u8]) -> Result<(u32, usize), FilterError> {
+    let mut value = 0u32;
+    for (i, byte) in data.iter().take(5).enumerate() {
+        value |= u32::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(FilterError::MalformedPacket)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u32) {
+    loop {
+        if value < 0x80 {
+            buffer.push(value as u8);
+            return;
+        }
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_varint, write_varint, ProtocolRule, ProtocolRules};
+
+    fn minecraft_status(json: &str) -> Vec<u8> {
+        let mut body = vec![0];
+        write_varint(&mut body, json.len() as u32);
+        body.extend_from_slice(json.as_bytes());
+        let mut packet = Vec::new();
+        write_varint(&mut packet, body.len() as u32);
+        packet.extend(body);
+        packet
+    }
+
+    #[test]
+    fn varints() {
+        for value in [0, 1, 127, 128, 300, 25565, u32::MAX] {
+            let mut buffer = Vec::new();
+            write_varint(&mut buffer, value);
+            assert_eq!(read_varint(&buffer).unwrap(), (value, buffer.len()));
+        }
+    }
+
+    #[test]
+    fn minecraft_fixup() {
+        // Long enough that the length prefixes change size
+        let original = format!(
+            "{{\"sample\":[{{\"name\":\"Notch\"}}],\"pad\":\"{}\"}}",
+            "x".repeat(120)
+        );
+        let redacted = original.replace("Notch", "player1");
+
+        let message = minecraft_status(&original);
+        let rewritten = ProtocolRules::Minecraft
+            .rewrite(&message, &mut |json| {
+                String::from_utf8_lossy(json)
+                    .replace("Notch", "player1")
+                    .into_bytes()
+            })
+            .unwrap();
+
+        assert_eq!(rewritten, minecraft_status(&redacted));
+    }
+
+    fn rename(data: &[u8]) -> Vec<u8> {
+        String::from_utf8_lossy(data)
+            .replace("Alice", "player1")
+            .into_bytes()
+    }
+
+    #[test]
+    fn source_strings() {
+        // The score and duration happen to contain "Alice" and must not change
+        let message = b"\xff\xff\xff\xffD\x02\0Alice\0Alice\0\x80\x3f\x01Bob\0\0\0\0\0\0\0\0\0";
+        assert_eq!(
+            ProtocolRules::Source.rewrite(message, &mut rename).unwrap(),
+            b"\xff\xff\xff\xffD\x02\0player1\0Alice\0\x80\x3f\x01Bob\0\0\0\0\0\0\0\0\0"
+        );
+
+        // A missing null terminator or unknown message isn't replaced at all
+        assert!(ProtocolRules::Source
+            .rewrite(b"\xff\xff\xff\xffD\x01\0Alice", &mut rename)
+            .is_err());
+        assert!(ProtocolRules::Source
+            .rewrite(b"\xff\xff\xff\xffXAlice\0", &mut rename)
+            .is_err());
+    }
+
+    #[test]
+    fn source_split() {
+        let split = |total: u8, number: u8, payload: &[u8]| {
+            let mut packet = b"\xfe\xff\xff\xff\x07\0\0\0".to_vec();
+            packet.extend([total, number, 0xe0, 0x04]);
+            packet.extend_from_slice(payload);
+            packet
+        };
+
+        // The name is split across both packets, which arrive out of order. The longer name no
+        // longer fits so another packet is added.
+        let first = split(2, 0, b"\xff\xff\xff\xffE\x01\0na");
+        let second = split(2, 1, b"me\0Alice\0");
+        let rewritten = ProtocolRules::Source
+            .rewrite_split(&[&second, &first], &mut rename)
+            .unwrap();
+        assert_eq!(
+            rewritten,
+            vec![
+                split(3, 0, b"\xff\xff\xff\xffE\x01\0na"),
+                split(3, 1, b"me\0player"),
+                split(3, 2, b"1\0"),
+            ]
+        );
+        assert_eq!(ProtocolRules::Source.split_id(&first), Some(7));
+        assert_eq!(ProtocolRules::Source.split_id(b"\xff\xff\xff\xffE"), None);
+
+        // Only one of the two packets
+        assert!(ProtocolRules::Source
+            .rewrite_split(&[&first], &mut rename)
+            .is_err());
+    }
+
+    #[test]
+    fn gamespy_header() {
+        // GameSpy 3 response whose session ID is "Alic" and a name in the following string
+        let message = b"\0Alicsplitnum\0\x80hostname\0Alice\0";
+        assert_eq!(
+            ProtocolRules::GameSpy
+                .rewrite(message, &mut rename)
+                .unwrap(),
+            b"\0Alicsplitnum\0\x80hostname\0player1\0"
+        );
+        assert_eq!(
+            ProtocolRules::GameSpy
+                .rewrite(b"\\player_0\\Alice\\", &mut rename)
+                .unwrap(),
+            b"\\player_0\\player1\\"
+        );
+    }
+
+    #[test]
+    fn rules_for_games() {
+        assert_eq!(
+            ProtocolRules::for_game("minecraft"),
+            ProtocolRules::Minecraft
+        );
+        assert_eq!(ProtocolRules::for_game("BF2"), ProtocolRules::GameSpy);
+        // Not minecraft java despite the prefix
+        assert_eq!(ProtocolRules::for_game("mcpe"), ProtocolRules::Source);
+        assert_eq!(ProtocolRules::for_game("csgo"), ProtocolRules::Source);
+    }
+
+    #[test]
+    fn replacement_checks() {
+        assert!(ProtocolRules::Source.check_replacement(b"a\0b").is_err());
+        assert!(ProtocolRules::GameSpy.check_replacement(b"a\\b").is_err());
+        assert!(ProtocolRules::Minecraft
+            .check_replacement(b"player1")
+            .is_ok());
+    }
+}
@@ -0,0 +1,458 @@
+//! Composable transformations of the packets in a replay
+//!
+//! ```
+//! use net_replay_test::packet::PacketDirection;
+//! use net_replay_test::packet_filter::transform::{Pipeline, RemapPort, SplitPackets};
+//!
+//! let pipeline = Pipeline::new()
+//!     // Drop an empty keep-alive the client sends
+//!     .retain(|packet| !packet.data.is_empty())
+//!     .then(RemapPort {
+//!         from: 27016,
+//!         to: 27015,
+//!     })
+//!     // Check the implementation copes with responses split into small TCP segments
+//!     .then(SplitPackets {
+//!         max_size: 16,
+//!         direction: Some(PacketDirection::FromServer),
+//!     });
+//! # let _ = pipeline;
+//! ```
+
+use std::collections::HashSet;
+
+use super::{redact_packets, redact_value, FilterError, ProtocolRule, Replacement, Replacer};
+use crate::options::ServerOptions;
+use crate::packet::{Packet, PacketDirection, PacketProtocol};
+use crate::value::CommonValue;
+use crate::QueryReplay;
+
+/// A step that rewrites the packets of a replay, and the expected value to match
+pub trait PacketTransform {
+    /// Transform the packets of a replay, packets are in the order they were captured
+    fn transform(&mut self, packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError>;
+
+    /// Update the expected value to match the transformed packets, called after
+    /// [PacketTransform::transform]
+    fn transform_value(&mut self, _value: &mut CommonValue) -> Result<(), FilterError> {
+        Ok(())
+    }
+}
+
+/// Transforms applied to a replay in order
+#[derive(Default)]
+pub struct Pipeline<'a> {
+    transforms: Vec<Box<dyn PacketTransform + 'a>>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a transform to the end of the pipeline
+    pub fn then(mut self, transform: impl PacketTransform + 'a) -> Self {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    /// Change each packet
+    pub fn map(self, f: impl FnMut(Packet) -> Packet + 'a) -> Self {
+        self.then(Map(f))
+    }
+
+    /// Keep only packets the predicate returns true for
+    pub fn retain(self, f: impl FnMut(&Packet) -> bool + 'a) -> Self {
+        self.then(Retain(f))
+    }
+
+    /// Replace each packet with any number of packets, e.g. to split or drop it
+    pub fn flat_map(self, f: impl FnMut(Packet) -> Vec<Packet> + 'a) -> Self {
+        self.then(FlatMap(f))
+    }
+
+    /// Change the expected value
+    pub fn map_value(self, f: impl FnMut(&mut CommonValue) + 'a) -> Self {
+        self.then(MapValue(f))
+    }
+
+    pub fn len(&self) -> usize {
+        self.transforms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    /// Apply every transform to a replay. Afterwards the server's packet size is updated, and
+    /// its ports are too if they can still be worked out from the packets. If a transform fails
+    /// the replay is left unchanged.
+    pub fn apply(&mut self, query_replay: &mut QueryReplay) -> Result<(), FilterError> {
+        let mut packets = query_replay.packets.clone();
+        let mut value = query_replay.value.clone();
+        for transform in &mut self.transforms {
+            packets = transform.transform(packets)?;
+            transform.transform_value(&mut value)?;
+        }
+
+        match ServerOptions::try_from(&packets[..]) {
+            Ok(server) => query_replay.server = server,
+            Err(_) => {
+                query_replay.server.packet_size = packets
+                    .iter()
+                    .map(|packet| packet.data.len())
+                    .max()
+                    .unwrap_or(0)
+            }
+        }
+        query_replay.packets = packets;
+        query_replay.value = value;
+
+        Ok(())
+    }
+}
+
+/// Change each packet, see [Pipeline::map]
+pub struct Map<F>(pub F);
+
+impl<F: FnMut(Packet) -> Packet> PacketTransform for Map<F> {
+    fn transform(&mut self, packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        Ok(packets.into_iter().map(&mut self.0).collect())
+    }
+}
+
+/// Keep only matching packets, see [Pipeline::retain]
+pub struct Retain<F>(pub F);
+
+impl<F: FnMut(&Packet) -> bool> PacketTransform for Retain<F> {
+    fn transform(&mut self, mut packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        packets.retain(&mut self.0);
+        Ok(packets)
+    }
+}
+
+/// Replace each packet with any number of packets, see [Pipeline::flat_map]
+pub struct FlatMap<F>(pub F);
+
+impl<F: FnMut(Packet) -> Vec<Packet>> PacketTransform for FlatMap<F> {
+    fn transform(&mut self, packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        Ok(packets.into_iter().flat_map(&mut self.0).collect())
+    }
+}
+
+/// Change the expected value, see [Pipeline::map_value]
+pub struct MapValue<F>(pub F);
+
+impl<F: FnMut(&mut CommonValue)> PacketTransform for MapValue<F> {
+    fn transform(&mut self, packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        Ok(packets)
+    }
+
+    fn transform_value(&mut self, value: &mut CommonValue) -> Result<(), FilterError> {
+        (self.0)(value);
+        Ok(())
+    }
+}
+
+/// Merge consecutive TCP packets sent in the same direction into one packet
+pub struct MergeTcp;
+
+impl PacketTransform for MergeTcp {
+    fn transform(&mut self, packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        let mut merged: Vec<Packet> = Vec::with_capacity(packets.len());
+        for packet in packets {
+            match merged.last_mut() {
+                Some(last)
+                    if packet.protocol == PacketProtocol::Tcp
+                        && last.protocol == PacketProtocol::Tcp
+                        && last.direction == packet.direction =>
+                {
+                    last.data.extend(packet.data);
+                }
+                _ => merged.push(packet),
+            }
+        }
+        Ok(merged)
+    }
+}
+
+/// Split TCP packets into packets of at most `max_size` bytes (at least 1), optionally only in
+/// one direction. UDP packets are kept whole as each datagram is a message.
+pub struct SplitPackets {
+    pub max_size: usize,
+    pub direction: Option<PacketDirection>,
+}
+
+impl PacketTransform for SplitPackets {
+    fn transform(&mut self, packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        let max_size = self.max_size.max(1);
+        let mut split = Vec::with_capacity(packets.len());
+        for packet in packets {
+            let matches_direction = self
+                .direction
+                .as_ref()
+                .map_or(true, |direction| *direction == packet.direction);
+            if packet.protocol != PacketProtocol::Tcp
+                || !matches_direction
+                || packet.data.len() <= max_size
+            {
+                split.push(packet);
+                continue;
+            }
+
+            for chunk in packet.data.chunks(max_size) {
+                split.push(Packet {
+                    data: chunk.to_vec(),
+                    ..packet.clone()
+                });
+            }
+        }
+        Ok(split)
+    }
+}
+
+/// Keep only flows with a request and a response, i.e. packets with the same protocol, server
+/// port and client port sent in both directions. Drops unrelated traffic to the same host such as
+/// unanswered connection attempts.
+pub struct KeepAnsweredFlows;
+
+impl PacketTransform for KeepAnsweredFlows {
+    fn transform(&mut self, mut packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        let mut requests = HashSet::new();
+        let mut responses = HashSet::new();
+        for packet in &packets {
+            match packet.direction {
+                PacketDirection::ToServer => requests.insert(flow(packet)),
+                PacketDirection::FromServer => responses.insert(flow(packet)),
+            };
+        }
+
+        packets.retain(|packet| {
+            let flow = flow(packet);
+            requests.contains(&flow) && responses.contains(&flow)
+        });
+        Ok(packets)
+    }
+}
+
+/// Keep only the flow of the first packet sent to the server, i.e. the query when the capture
+/// started just before it. Drops other flows to the same host such as an SSH session, use
+/// [KeepAnsweredFlows] first so an unanswered packet isn't taken as the query.
+pub struct KeepFirstFlow;
+
+impl PacketTransform for KeepFirstFlow {
+    fn transform(&mut self, mut packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        let Some(first) = packets
+            .iter()
+            .find(|packet| packet.direction == PacketDirection::ToServer)
+        else {
+            return Ok(packets);
+        };
+        let query = flow(first);
+
+        packets.retain(|packet| flow(packet) == query);
+        Ok(packets)
+    }
+}
+
+/// The protocol, server port and client port of a packet
+fn flow(packet: &Packet) -> (PacketProtocol, u16, u16) {
+    match packet.direction {
+        PacketDirection::ToServer => (packet.protocol.clone(), packet.dst_port, packet.src_port),
+        PacketDirection::FromServer => (packet.protocol.clone(), packet.src_port, packet.dst_port),
+    }
+}
+
+/// Change the server's port, e.g. so replays captured from different ports share a port
+pub struct RemapPort {
+    pub from: u16,
+    pub to: u16,
+}
+
+impl PacketTransform for RemapPort {
+    fn transform(&mut self, mut packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        for packet in &mut packets {
+            let port = match packet.direction {
+                PacketDirection::ToServer => &mut packet.dst_port,
+                PacketDirection::FromServer => &mut packet.src_port,
+            };
+            if *port == self.from {
+                *port = self.to;
+            }
+        }
+        Ok(packets)
+    }
+}
+
+/// Replace bytes in packets from the server and strings in the expected value, see
+/// [super::redact_replay]
+pub struct Redact<'a> {
+    pub replacements: Vec<Replacement>,
+    pub rule: &'a dyn ProtocolRule,
+    /// Replacements for the expected value, if unset the packet replacements are used
+    pub value_replacements: Option<Vec<Replacement>>,
+    /// How many times each pattern was replaced in packets
+    pub counts: Vec<usize>,
+}
+
+impl<'a> Redact<'a> {
+    pub fn new(replacements: Vec<Replacement>, rule: &'a dyn ProtocolRule) -> Self {
+        Self {
+            replacements,
+            rule,
+            value_replacements: None,
+            counts: Vec::new(),
+        }
+    }
+}
+
+impl PacketTransform for Redact<'_> {
+    fn transform(&mut self, packets: Vec<Packet>) -> Result<Vec<Packet>, FilterError> {
+        let replacer = Replacer::new(&self.replacements)?;
+        let (packets, counts) = redact_packets(packets, &replacer, self.rule)?;
+        self.counts = counts;
+        Ok(packets)
+    }
+
+    fn transform_value(&mut self, value: &mut CommonValue) -> Result<(), FilterError> {
+        let replacements = self
+            .value_replacements
+            .as_ref()
+            .unwrap_or(&self.replacements);
+        redact_value(value, &Replacer::new(replacements)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SplitPackets;
+    use super::{KeepAnsweredFlows, MergeTcp, PacketTransform, Pipeline, Redact, RemapPort};
+    use crate::options::ServerOptions;
+    use crate::packet::{Packet, PacketDirection, PacketProtocol};
+    use crate::packet_filter::ProtocolRules;
+    use crate::value::CommonValue;
+    use crate::{QueryOptions, QueryReplay, REPLAY_VERSION};
+
+    fn packet(direction: PacketDirection, data: &[u8]) -> Packet {
+        let (src_port, dst_port) = match direction {
+            PacketDirection::ToServer => (4000, 25566),
+            PacketDirection::FromServer => (25566, 4000),
+        };
+        Packet {
+            direction,
+            protocol: PacketProtocol::Tcp,
+            src_port,
+            dst_port,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn pipeline() {
+        let mut replay = QueryReplay {
+            query: QueryOptions {
+                address: "192.0.2.1".to_string(),
+                port: Some(25566),
+                game: "minecraft".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: Some(25566),
+                udp_port: None,
+                packet_size: 8,
+            },
+            packets: vec![
+                packet(PacketDirection::ToServer, b"status"),
+                packet(PacketDirection::ToServer, b""),
+                packet(PacketDirection::FromServer, b"Notch "),
+                packet(PacketDirection::FromServer, b"and jeb_"),
+            ],
+            value: CommonValue {
+                name: None,
+                map: None,
+                has_password: None,
+                players_online: Some(2),
+                players_maximum: None,
+                player_names: ["Notch".to_string(), "jeb_".to_string()]
+                    .into_iter()
+                    .collect(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+
+        // A failing transform leaves the replay as it was
+        let original = replay.clone();
+        let source = ProtocolRules::Source;
+        let result = Pipeline::new()
+            .map_value(|value| value.players_online = Some(1))
+            .then(Redact::new(
+                vec![(b"Notch".to_vec(), b"player1".to_vec())],
+                &source,
+            ))
+            .apply(&mut replay);
+        assert!(result.is_err());
+        let data = |replay: &QueryReplay| -> Vec<Vec<u8>> {
+            replay
+                .packets
+                .iter()
+                .map(|packet| packet.data.clone())
+                .collect()
+        };
+        assert_eq!(data(&replay), data(&original));
+        assert_eq!(replay.value, original.value);
+
+        let rule = ProtocolRules::Raw;
+        Pipeline::new()
+            .retain(|packet| !packet.data.is_empty())
+            .then(MergeTcp)
+            .then(Redact::new(
+                vec![(b"Notch".to_vec(), b"player1".to_vec())],
+                &rule,
+            ))
+            .then(SplitPackets {
+                max_size: 5,
+                direction: Some(PacketDirection::FromServer),
+            })
+            .then(RemapPort {
+                from: 25566,
+                to: 25565,
+            })
+            .map_value(|value| value.players_online = Some(1))
+            .apply(&mut replay)
+            .unwrap();
+
+        let data: Vec<&[u8]> = replay
+            .packets
+            .iter()
+            .map(|packet| &packet.data[..])
+            .collect();
+        assert_eq!(
+            data,
+            vec![&b"status"[..], b"playe", b"r1 an", b"d jeb", b"_"]
+        );
+        assert_eq!(replay.server.tcp_port, Some(25565));
+        assert_eq!(replay.server.packet_size, 6);
+        assert!(replay.value.player_names.contains("player1"));
+        assert_eq!(replay.value.players_online, Some(1));
+    }
+
+    #[test]
+    fn answered_flows() {
+        let mut unanswered = packet(PacketDirection::ToServer, b"ssh");
+        unanswered.dst_port = 22;
+        let mut other_client = packet(PacketDirection::FromServer, b"late");
+        other_client.dst_port = 4001;
+
+        let packets = vec![
+            packet(PacketDirection::ToServer, b"status"),
+            unanswered,
+            packet(PacketDirection::FromServer, b"response"),
+            other_client,
+        ];
+        let kept = KeepAnsweredFlows.transform(packets).unwrap();
+
+        let data: Vec<&[u8]> = kept.iter().map(|packet| &packet.data[..]).collect();
+        assert_eq!(data, vec![&b"status"[..], b"response"]);
+    }
+}
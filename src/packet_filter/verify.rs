@@ -0,0 +1,186 @@
+//! Checking that redacted strings are really gone from a replay
+
+use super::names::{encodings, find_with_color_codes};
+use super::FilterError;
+use crate::packet::PacketDirection;
+use crate::QueryReplay;
+
+/// Strings shorter than this aren't checked, they would match unrelated bytes in packets
+pub const MIN_LEAK_LEN: usize = 3;
+
+/// A redacted string that is still present in a replay
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leak {
+    /// The original string
+    pub original: String,
+    /// How it was written, e.g. "UTF-16LE"
+    pub encoding: &'static str,
+    /// Index of the packet it was found in, or None if it was found in the expected value
+    pub packet: Option<usize>,
+    pub direction: Option<PacketDirection>,
+    /// Offset in the packet data
+    pub offset: usize,
+}
+
+impl std::fmt::Display for Leak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.packet, &self.direction) {
+            (Some(packet), Some(direction)) => write!(
+                f,
+                "{:?} ({}) in packet {} ({:?}) at offset {}",
+                self.original, self.encoding, packet, direction, self.offset
+            ),
+            _ => write!(f, "{:?} in the expected value", self.original),
+        }
+    }
+}
+
+/// Scan every packet, in both directions, and the strings in the expected value for the original
+/// strings, also as UTF-16LE, Latin-1 and with color codes between characters. Originals shorter
+/// than [MIN_LEAK_LEN] are skipped.
+pub fn find_leaks(query_replay: &QueryReplay, originals: &[Vec<u8>]) -> Vec<Leak> {
+    let mut leaks = Vec::new();
+
+    for original in originals.iter().filter(|o| o.len() >= MIN_LEAK_LEN) {
+        let display = String::from_utf8_lossy(original).into_owned();
+        let encoded = encodings(original);
+
+        for (i, packet) in query_replay.packets.iter().enumerate() {
+            let mut leak = |encoding, offset| {
+                leaks.push(Leak {
+                    original: display.clone(),
+                    encoding,
+                    packet: Some(i),
+                    direction: Some(packet.direction.clone()),
+                    offset,
+                })
+            };
+
+            for (encoding, needle) in &encoded {
+                for offset in find_all(&packet.data, needle) {
+                    leak(*encoding, offset);
+                }
+            }
+            for form in find_with_color_codes(&packet.data, original) {
+                for offset in find_all(&packet.data, &form) {
+                    leak("color coded", offset);
+                }
+            }
+        }
+
+        let value = &query_replay.value;
+        let in_value = value
+            .name
+            .iter()
+            .chain(value.map.iter())
+            .chain(value.player_names.iter())
+            .any(|s| !find_all(s.as_bytes(), original).is_empty());
+        if in_value {
+            leaks.push(Leak {
+                original: display.clone(),
+                encoding: "UTF-8",
+                packet: None,
+                direction: None,
+                offset: 0,
+            });
+        }
+    }
+
+    leaks
+}
+
+/// Check none of the original strings are left in a replay, see [find_leaks]
+pub fn verify_redaction(
+    query_replay: &QueryReplay,
+    originals: &[Vec<u8>],
+) -> Result<(), FilterError> {
+    let leaks = find_leaks(query_replay, originals);
+    if leaks.is_empty() {
+        Ok(())
+    } else {
+        Err(FilterError::Leaked(leaks))
+    }
+}
+
+/// Print each leak on its own line
+pub fn print_leak_report(leaks: &[Leak]) {
+    println!("Redacted strings found {} times:", leaks.len());
+    for leak in leaks {
+        println!("  {}", leak);
+    }
+}
+
+fn find_all(data: &[u8], needle: &[u8]) -> Vec<usize> {
+    if needle.is_empty() {
+        return Vec::new();
+    }
+    data.windows(needle.len())
+        .enumerate()
+        .filter(|(_, window)| *window == needle)
+        .map(|(offset, _)| offset)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::find_leaks;
+    use crate::packet::{Packet, PacketDirection, PacketProtocol};
+    use crate::value::CommonValue;
+    use crate::{options::ServerOptions, QueryOptions, QueryReplay, REPLAY_VERSION};
+
+    #[test]
+    fn leaks() {
+        let packet = |direction, data: &[u8]| Packet {
+            direction,
+            protocol: PacketProtocol::Udp,
+            src_port: 0,
+            dst_port: 0,
+            data: data.to_vec(),
+        };
+        let replay = QueryReplay {
+            query: QueryOptions {
+                address: "192.0.2.1".to_string(),
+                port: None,
+                game: "csgo".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: None,
+                udp_port: Some(27015),
+                packet_size: 0,
+            },
+            packets: vec![
+                packet(PacketDirection::ToServer, b"join Alice"),
+                packet(PacketDirection::FromServer, b"\0A\0l\0i\0c\0e\0\0player1\0"),
+                packet(PacketDirection::FromServer, b"^1Ali^2ce\0"),
+            ],
+            value: CommonValue {
+                name: None,
+                map: None,
+                has_password: None,
+                players_online: None,
+                players_maximum: None,
+                player_names: ["player1".to_string()].into_iter().collect(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+
+        let leaks = find_leaks(&replay, &[b"Alice".to_vec(), b"Al".to_vec()]);
+        let found: Vec<_> = leaks
+            .iter()
+            .map(|leak| (leak.packet, leak.encoding, leak.offset))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Some(0), "UTF-8", 5),
+                (Some(1), "UTF-16LE", 1),
+                (Some(2), "color coded", 0)
+            ]
+        );
+
+        assert!(find_leaks(&replay, &[b"player1".to_vec()])
+            .iter()
+            .any(|leak| leak.packet.is_none()));
+    }
+}
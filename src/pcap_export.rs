@@ -0,0 +1,338 @@
+//! Write a replay's packets as a pcap file that can be opened in Wireshark
+//!
+//! Replays don't store addresses or timestamps so the client is given the address 10.0.0.1, the
+//! server 10.0.0.2 and packets are 1ms apart. TCP connections get a synthetic handshake so that
+//! streams can be followed.
+
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use crate::packet::{PacketDirection, PacketProtocol};
+use crate::{Error, QueryReplay};
+
+/// Address given to the client in exported pcaps
+pub const CLIENT_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+/// Address given to the server in exported pcaps
+pub const SERVER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Link type for packets starting at the IP header
+const LINKTYPE_RAW: u32 = 101;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const TCP_HEADER_LEN: usize = 20;
+const MAX_IP_PAYLOAD: usize = u16::MAX as usize - IPV4_HEADER_LEN;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Export a replay to a pcap file, see [write_pcap]
+pub fn export_pcap(query_replay: &QueryReplay, path: impl AsRef<Path>) -> Result<(), Error> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write_pcap(query_replay, &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Write a replay's packets in the classic pcap format with synthesized IPv4, UDP and TCP
+/// headers
+pub fn write_pcap(query_replay: &QueryReplay, writer: impl Write) -> Result<(), Error> {
+    let mut pcap = PcapWriter::new(writer)?;
+    let mut tcp: Option<TcpState> = None;
+
+    for packet in &query_replay.packets {
+        let (client_port, server_port) = match packet.direction {
+            PacketDirection::ToServer => (packet.src_port, packet.dst_port),
+            PacketDirection::FromServer => (packet.dst_port, packet.src_port),
+        };
+        let to_server = packet.direction == PacketDirection::ToServer;
+
+        match packet.protocol {
+            PacketProtocol::Udp => {
+                if packet.data.len() > MAX_IP_PAYLOAD - UDP_HEADER_LEN {
+                    return Err(Error::String(format!(
+                        "UDP packet of {} bytes is too large to export",
+                        packet.data.len()
+                    )));
+                }
+                pcap.write_ip(to_server, 17, &udp_segment(packet, to_server))?;
+            }
+            PacketProtocol::Tcp => {
+                let state = match &mut tcp {
+                    Some(state) if state.ports == (client_port, server_port) => state,
+                    _ => {
+                        if let Some(state) = tcp.take() {
+                            state.close(&mut pcap)?;
+                        }
+                        tcp.insert(TcpState::open(&mut pcap, client_port, server_port)?)
+                    }
+                };
+
+                for chunk in packet.data.chunks(MAX_IP_PAYLOAD - TCP_HEADER_LEN) {
+                    state.send(&mut pcap, to_server, TCP_PSH | TCP_ACK, chunk)?;
+                }
+            }
+        }
+    }
+
+    if let Some(state) = tcp {
+        state.close(&mut pcap)?;
+    }
+
+    Ok(())
+}
+
+struct PcapWriter<W: Write> {
+    writer: W,
+    /// Synthetic time of the next packet in microseconds
+    time: u64,
+    ip_id: u16,
+}
+
+impl<W: Write> PcapWriter<W> {
+    fn new(mut writer: W) -> std::io::Result<Self> {
+        writer.write_all(&0xa1b2c3d4u32.to_le_bytes())?; // Magic (microsecond timestamps)
+        writer.write_all(&2u16.to_le_bytes())?; // Major version
+        writer.write_all(&4u16.to_le_bytes())?; // Minor version
+        writer.write_all(&0i32.to_le_bytes())?; // Timezone
+        writer.write_all(&0u32.to_le_bytes())?; // Timestamp accuracy
+        writer.write_all(&(u16::MAX as u32).to_le_bytes())?; // Snapshot length
+        writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            time: 0,
+            ip_id: 1,
+        })
+    }
+
+    /// Write an IPv4 packet containing a transport segment
+    fn write_ip(&mut self, to_server: bool, protocol: u8, segment: &[u8]) -> std::io::Result<()> {
+        let (src, dst) = addresses(to_server);
+        let total_len = (IPV4_HEADER_LEN + segment.len()) as u16;
+
+        let mut header = [0u8; IPV4_HEADER_LEN];
+        header[0] = 0x45; // Version 4, 5 word header
+        header[2..4].copy_from_slice(&total_len.to_be_bytes());
+        header[4..6].copy_from_slice(&self.ip_id.to_be_bytes());
+        header[6] = 0x40; // Don't fragment
+        header[8] = 64; // TTL
+        header[9] = protocol;
+        header[12..16].copy_from_slice(&src.octets());
+        header[16..20].copy_from_slice(&dst.octets());
+        let checksum = checksum(&[&header]);
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        self.ip_id = self.ip_id.wrapping_add(1);
+
+        let len = total_len as u32;
+        self.writer
+            .write_all(&((self.time / 1_000_000) as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&((self.time % 1_000_000) as u32).to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?; // Captured length
+        self.writer.write_all(&len.to_le_bytes())?; // Original length
+        self.writer.write_all(&header)?;
+        self.writer.write_all(segment)?;
+
+        self.time += 1000;
+        Ok(())
+    }
+}
+
+/// Sequence numbers for a synthesized TCP connection
+struct TcpState {
+    ports: (u16, u16),
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl TcpState {
+    fn open<W: Write>(
+        pcap: &mut PcapWriter<W>,
+        client_port: u16,
+        server_port: u16,
+    ) -> std::io::Result<Self> {
+        let mut state = Self {
+            ports: (client_port, server_port),
+            client_seq: 1000,
+            server_seq: 5000,
+        };
+        state.send(pcap, true, TCP_SYN, &[])?;
+        state.send(pcap, false, TCP_SYN | TCP_ACK, &[])?;
+        state.send(pcap, true, TCP_ACK, &[])?;
+        Ok(state)
+    }
+
+    fn close<W: Write>(mut self, pcap: &mut PcapWriter<W>) -> std::io::Result<()> {
+        self.send(pcap, true, TCP_FIN | TCP_ACK, &[])?;
+        self.send(pcap, false, TCP_FIN | TCP_ACK, &[])?;
+        self.send(pcap, true, TCP_ACK, &[])
+    }
+
+    fn send<W: Write>(
+        &mut self,
+        pcap: &mut PcapWriter<W>,
+        to_server: bool,
+        flags: u8,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let (src_port, dst_port) = if to_server {
+            self.ports
+        } else {
+            (self.ports.1, self.ports.0)
+        };
+        let (seq, ack) = if to_server {
+            (self.client_seq, self.server_seq)
+        } else {
+            (self.server_seq, self.client_seq)
+        };
+
+        let mut segment = vec![0u8; TCP_HEADER_LEN];
+        segment[0..2].copy_from_slice(&src_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        segment[4..8].copy_from_slice(&seq.to_be_bytes());
+        // The first SYN doesn't acknowledge anything
+        if flags & TCP_ACK != 0 {
+            segment[8..12].copy_from_slice(&ack.to_be_bytes());
+        }
+        segment[12] = ((TCP_HEADER_LEN / 4) as u8) << 4;
+        segment[13] = flags;
+        segment[14..16].copy_from_slice(&u16::MAX.to_be_bytes()); // Window
+        segment.extend_from_slice(data);
+
+        let checksum = transport_checksum(to_server, 6, &segment);
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+        // SYN and FIN each use a sequence number
+        let used = data.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        if to_server {
+            self.client_seq = self.client_seq.wrapping_add(used);
+        } else {
+            self.server_seq = self.server_seq.wrapping_add(used);
+        }
+
+        pcap.write_ip(to_server, 6, &segment)
+    }
+}
+
+fn udp_segment(packet: &crate::packet::Packet, to_server: bool) -> Vec<u8> {
+    let len = (UDP_HEADER_LEN + packet.data.len()) as u16;
+
+    let mut segment = Vec::with_capacity(len as usize);
+    segment.extend_from_slice(&packet.src_port.to_be_bytes());
+    segment.extend_from_slice(&packet.dst_port.to_be_bytes());
+    segment.extend_from_slice(&len.to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(&packet.data);
+
+    let checksum = match transport_checksum(to_server, 17, &segment) {
+        // A checksum of 0 means no checksum for UDP
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    segment[6..8].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+fn addresses(to_server: bool) -> (Ipv4Addr, Ipv4Addr) {
+    if to_server {
+        (CLIENT_ADDRESS, SERVER_ADDRESS)
+    } else {
+        (SERVER_ADDRESS, CLIENT_ADDRESS)
+    }
+}
+
+/// Checksum of a TCP or UDP segment including the IPv4 pseudo header
+fn transport_checksum(to_server: bool, protocol: u8, segment: &[u8]) -> u16 {
+    let (src, dst) = addresses(to_server);
+    let len = (segment.len() as u16).to_be_bytes();
+    checksum(&[&src.octets(), &dst.octets(), &[0, protocol], &len, segment])
+}
+
+/// Internet checksum (RFC 1071) over the concatenation of the parts, every part except the last
+/// must have an even length
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match word {
+                [a, b] => u16::from_be_bytes([*a, *b]),
+                [a] => u16::from_be_bytes([*a, 0]),
+                _ => unreachable!(),
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{checksum, write_pcap};
+    use crate::options::{QueryOptions, ServerOptions};
+    use crate::packet::{Packet, PacketDirection, PacketProtocol};
+    use crate::value::CommonValue;
+    use crate::{QueryReplay, REPLAY_VERSION};
+
+    #[test]
+    fn export_udp() {
+        let packet = |direction, data: &[u8]| {
+            let (src_port, dst_port) = match direction {
+                PacketDirection::ToServer => (40000, 27015),
+                PacketDirection::FromServer => (27015, 40000),
+            };
+            Packet {
+                direction,
+                protocol: PacketProtocol::Udp,
+                src_port,
+                dst_port,
+                data: data.to_vec(),
+            }
+        };
+        let replay = QueryReplay {
+            query: QueryOptions {
+                address: "127.0.0.1".to_string(),
+                port: Some(27015),
+                game: "csgo".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: None,
+                udp_port: Some(27015),
+                packet_size: 4,
+            },
+            packets: vec![
+                packet(PacketDirection::ToServer, b"ping"),
+                packet(PacketDirection::FromServer, b"pong"),
+            ],
+            value: CommonValue {
+                name: None,
+                map: None,
+                has_password: None,
+                players_online: None,
+                players_maximum: None,
+                player_names: Default::default(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+
+        let mut pcap = Vec::new();
+        write_pcap(&replay, &mut pcap).unwrap();
+
+        // Global header, then 2 records of 16 byte header + 20 byte IP + 8 byte UDP + 4 byte data
+        assert_eq!(pcap.len(), 24 + 2 * (16 + 20 + 8 + 4));
+        assert_eq!(&pcap[20..24], &101u32.to_le_bytes());
+
+        let ip = &pcap[24 + 16..24 + 16 + 20];
+        assert_eq!(ip[0], 0x45);
+        assert_eq!(checksum(&[ip]), 0);
+        assert_eq!(&ip[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&pcap[24 + 16 + 28..24 + 16 + 32], b"ping");
+    }
+}
@@ -0,0 +1,249 @@
+//! JUnit XML and JSON reports of batch replay runs
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::Path;
+
+use crate::batch::{ReplayRun, ReplayStatus};
+use crate::server::PacketMismatch;
+use crate::value::FieldDifference;
+use crate::Error;
+
+/// Report of replaying a set of files with one implementation
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Report {
+    pub implementation: String,
+    pub entries: Vec<ReportEntry>,
+}
+
+/// The result of a single replay file
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ReportEntry {
+    pub path: String,
+    pub status: ReplayStatus,
+    /// Time taken in seconds
+    pub duration: f64,
+    /// Fields where the produced value differed from the stored value
+    pub differences: Vec<FieldDifference>,
+    pub packet_mismatches: Vec<PacketMismatch>,
+    /// Whether the server played back every packet
+    pub complete: bool,
+    pub error: Option<String>,
+}
+
+impl Report {
+    pub fn new(implementation: &str, runs: &[ReplayRun]) -> Self {
+        let entries = runs
+            .iter()
+            .map(|run| ReportEntry {
+                path: run.path.display().to_string(),
+                status: run.status,
+                duration: run.duration.as_secs_f64(),
+                differences: run
+                    .outcome
+                    .as_ref()
+                    .map(|outcome| outcome.expected.differences(&outcome.value))
+                    .unwrap_or_default(),
+                packet_mismatches: run
+                    .outcome
+                    .as_ref()
+                    .map(|outcome| outcome.packet_mismatches.clone())
+                    .unwrap_or_default(),
+                complete: run.outcome.as_ref().is_some_and(|outcome| outcome.complete),
+                error: run.error.clone(),
+            })
+            .collect();
+
+        Self {
+            implementation: implementation.to_string(),
+            entries,
+        }
+    }
+
+    pub fn write_json(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn write_junit(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        file.write_all(self.to_junit().as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Format the report as JUnit XML with one test case per replay file. Value and packet
+    /// mismatches are failures, errors and wrong replay versions are errors.
+    pub fn to_junit(&self) -> String {
+        let count = |status| {
+            self.entries
+                .iter()
+                .filter(|entry| entry.status == status)
+                .count()
+        };
+        let total_time: f64 = self.entries.iter().map(|entry| entry.duration).sum();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuites>\n  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+            escape(&self.implementation),
+            self.entries.len(),
+            count(ReplayStatus::Mismatch),
+            count(ReplayStatus::Error) + count(ReplayStatus::WrongVersion),
+            total_time
+        );
+
+        for entry in &self.entries {
+            let _ = write!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                escape(&self.implementation),
+                escape(&entry.path),
+                entry.duration
+            );
+
+            let details = entry.details();
+            let (tag, kind) = match entry.status {
+                ReplayStatus::Pass if details.is_empty() => {
+                    xml.push_str(" />\n");
+                    continue;
+                }
+                ReplayStatus::Pass => ("system-out", ""),
+                ReplayStatus::Mismatch => ("failure", "mismatch"),
+                ReplayStatus::Error => ("error", "error"),
+                ReplayStatus::WrongVersion => ("error", "version"),
+            };
+
+            xml.push_str(">\n");
+            if kind.is_empty() {
+                let _ = writeln!(xml, "      <{}>{}</{}>", tag, escape(&details), tag);
+            } else {
+                let message = match &entry.error {
+                    Some(error) => error.clone(),
+                    None if entry.differences.is_empty() => {
+                        format!("{} packet(s) differ", entry.packet_mismatches.len())
+                    }
+                    None => format!("{} field(s) differ", entry.differences.len()),
+                };
+                let _ = writeln!(
+                    xml,
+                    "      <{} type=\"{}\" message=\"{}\">{}</{}>",
+                    tag,
+                    kind,
+                    escape(&message),
+                    escape(&details),
+                    tag
+                );
+            }
+            xml.push_str("    </testcase>\n");
+        }
+
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+}
+
+impl ReportEntry {
+    /// Human readable value diff and packet mismatches
+    fn details(&self) -> String {
+        let mut details = String::new();
+        if let Some(error) = &self.error {
+            let _ = writeln!(details, "{}", error);
+        }
+        for diff in &self.differences {
+            let _ = writeln!(
+                details,
+                "\"{}\" => expected({}) value({})",
+                diff.field, diff.expected, diff.value
+            );
+        }
+        for mismatch in &self.packet_mismatches {
+            let _ = writeln!(
+                details,
+                "packet {} ({:?}) expected {} received {}",
+                mismatch.index,
+                mismatch.protocol,
+                hex(&mismatch.expected),
+                hex(&mismatch.received)
+            );
+        }
+        if !self.complete && self.error.is_none() {
+            details.push_str("not all packets were played back\n");
+        }
+        details
+    }
+}
+
+pub(crate) fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Escape text for use in XML attributes and elements, dropping characters that aren't allowed
+/// in XML 1.0
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape, Report, ReportEntry};
+    use crate::batch::ReplayStatus;
+    use crate::value::FieldDifference;
+
+    #[test]
+    fn junit_report() {
+        let report = Report {
+            implementation: "rust".to_string(),
+            entries: vec![
+                ReportEntry {
+                    path: "a.json".to_string(),
+                    status: ReplayStatus::Pass,
+                    duration: 0.5,
+                    differences: Vec::new(),
+                    packet_mismatches: Vec::new(),
+                    complete: true,
+                    error: None,
+                },
+                ReportEntry {
+                    path: "b.json".to_string(),
+                    status: ReplayStatus::Mismatch,
+                    duration: 0.25,
+                    differences: vec![FieldDifference {
+                        field: "name".to_string(),
+                        expected: "Some(\"<a>\")".to_string(),
+                        value: "None".to_string(),
+                    }],
+                    packet_mismatches: Vec::new(),
+                    complete: true,
+                    error: None,
+                },
+            ],
+        };
+
+        let xml = report.to_junit();
+        assert!(xml.contains("tests=\"2\" failures=\"1\" errors=\"0\""));
+        assert!(xml.contains("<testcase classname=\"rust\" name=\"a.json\" time=\"0.500\" />"));
+        assert!(xml.contains("<failure type=\"mismatch\" message=\"1 field(s) differ\">&quot;name&quot; =&gt; expected(Some(&quot;&lt;a&gt;&quot;)) value(None)\n</failure>"));
+    }
+
+    #[test]
+    fn xml_escape() {
+        assert_eq!(escape("a<&>\"'\u{1}\n"), "a&lt;&amp;&gt;&quot;&apos;\n");
+    }
+}
@@ -1,140 +1,300 @@
-use std::cell::OnceCell;
+//! Replay server that plays back the server side of a [QueryReplay]
+
 use std::io::{Read, Write};
 use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
-use std::sync::{Arc, Barrier};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use crate::error::EResult;
 use crate::packet::{Packet, PacketDirection, PacketProtocol};
 use crate::{Error, QueryReplay};
 
-pub type ReadyBarrier = Arc<Barrier>;
+/// A packet received by the server that wasn't the same as the captured packet
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct PacketMismatch {
+    /// Index of the packet in the replay
+    pub index: usize,
+    pub protocol: PacketProtocol,
+    pub expected: Vec<u8>,
+    pub received: Vec<u8>,
+}
 
-pub fn server(address: IpAddr, query_replay: QueryReplay, ready: ReadyBarrier) -> EResult<()> {
-    let mut packet_pos = 0;
-    let packet_count = query_replay.packets.len();
-    let mut buf = vec![0u8; query_replay.server.packet_size];
-    let udp_client_addr = OnceCell::new();
-    let tcp_stream = OnceCell::new();
+/// Replay server bound to the ports used in a replay, only the protocols used in the replay are
+/// bound. A port of 0 in the replay binds any free port.
+pub struct Server {
+    query_replay: QueryReplay,
+    tcp_listener: Option<TcpListener>,
+    udp_socket: Option<UdpSocket>,
+    timeout: Option<Duration>,
+}
 
-    let tcp_listener = std::net::TcpListener::bind(SocketAddr::new(
-        address,
-        query_replay.server.tcp_port.unwrap_or(60000),
-    ))?;
-    let udp_listener = std::net::UdpSocket::bind(SocketAddr::new(
-        address,
-        query_replay.server.udp_port.unwrap_or(60000),
-    ))?;
+impl Server {
+    pub fn bind(address: IpAddr, query_replay: QueryReplay) -> EResult<Self> {
+        let tcp_listener = match query_replay.server.tcp_port {
+            Some(port) => Some(TcpListener::bind(SocketAddr::new(address, port))?),
+            None => None,
+        };
+        let udp_socket = match query_replay.server.udp_port {
+            Some(port) => Some(UdpSocket::bind(SocketAddr::new(address, port))?),
+            None => None,
+        };
 
-    ready.wait();
+        Ok(Self {
+            query_replay,
+            tcp_listener,
+            udp_socket,
+            timeout: None,
+        })
+    }
 
-    while packet_pos < packet_count {
-        let packet = &query_replay.packets[packet_pos];
+    pub fn tcp_address(&self) -> Option<SocketAddr> {
+        self.tcp_listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
 
-        let state = match (&packet.direction, &packet.protocol) {
-            (PacketDirection::ToServer, PacketProtocol::Tcp) => {
-                handle_tcp_receive(&mut buf, &tcp_stream, &tcp_listener, packet)
-            }
-            (PacketDirection::ToServer, PacketProtocol::Udp) => {
-                handle_udp_receive(&mut buf, &udp_client_addr, &udp_listener, packet)
-            }
-            (PacketDirection::FromServer, PacketProtocol::Tcp) => {
-                handle_tcp_send(packet, &tcp_stream)
-            }
-            (PacketDirection::FromServer, PacketProtocol::Udp) => {
-                handle_udp_send(packet, &udp_client_addr, &udp_listener)
-            }
-        }?;
+    pub fn udp_address(&self) -> Option<SocketAddr> {
+        self.udp_socket
+            .as_ref()
+            .and_then(|socket| socket.local_addr().ok())
+    }
 
-        if state == HandleState::Complete {
-            packet_pos += 1;
+    /// Give up waiting for the client after this long, by default the server waits forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> EResult<()> {
+        if let Some(udp_socket) = &self.udp_socket {
+            udp_socket.set_read_timeout(timeout)?;
         }
+        self.timeout = timeout;
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Run the server on a new thread, as [Error] can't be sent between threads any errors other
+    /// than IO errors are converted to strings
+    pub fn spawn(self) -> JoinHandle<std::io::Result<Vec<PacketMismatch>>> {
+        std::thread::spawn(move || self.run().map_err(into_io_error))
+    }
 
-#[derive(Clone, Debug, PartialEq)]
-enum HandleState {
-    Incomplete,
-    Complete,
-}
+    /// Run the server on a new thread, returning the received packets that didn't match along
+    /// with whether every packet was played back, the server stops early if it times out
+    /// waiting for the client
+    pub(crate) fn spawn_playback(self) -> JoinHandle<(Vec<PacketMismatch>, std::io::Result<()>)> {
+        std::thread::spawn(move || {
+            let mut mismatches = Vec::new();
+            let result = self.play(&mut mismatches).map_err(into_io_error);
+            (mismatches, result)
+        })
+    }
 
-fn handle_tcp_receive(
-    buf: &mut [u8],
-    tcp_stream: &OnceCell<TcpStream>,
-    tcp_listener: &TcpListener,
-    packet: &Packet,
-) -> EResult<HandleState> {
-    let state = if let Some(mut stream) = tcp_stream.get() {
-        let _size = stream.read(buf)?;
-        // TODO: Compare data
-        HandleState::Complete
-    } else {
-        let (mut stream, _address) = tcp_listener.accept()?;
+    /// Play back the replay's packets, returning the received packets that didn't match
+    pub fn run(self) -> EResult<Vec<PacketMismatch>> {
+        let mut mismatches = Vec::new();
+        self.play(&mut mismatches)?;
+        Ok(mismatches)
+    }
 
-        let size = stream.read(buf)?;
-        // TODO: Compare data
+    fn play(&self, mismatches: &mut Vec<PacketMismatch>) -> EResult<()> {
+        // Received packets can be larger than any that were captured
+        let mut buf = vec![0u8; self.query_replay.server.packet_size.max(u16::MAX as usize)];
+        let mut udp_client_addr = None;
+        let mut tcp_stream = None;
+        let mut playback = Playback::new(&self.query_replay.packets, mismatches);
 
-        if buf[..size].ne(&packet.data) {
-            println!("Received TCP packet that didn't match");
+        loop {
+            match playback.next_step() {
+                Step::ReadTcp => {
+                    let stream = match &mut tcp_stream {
+                        Some(stream) => stream,
+                        None => tcp_stream.insert(self.accept()?),
+                    };
+                    let size = stream.read(&mut buf)?;
+                    playback.tcp_received(&buf[..size]);
+                }
+                Step::RecvUdp => {
+                    let udp_socket = self.udp_socket.as_ref().ok_or_else(no_socket)?;
+                    let (size, client_addr) = udp_socket.recv_from(&mut buf)?;
+                    udp_client_addr.get_or_insert(client_addr);
+                    playback.udp_received(&buf[..size]);
+                }
+                Step::SendTcp(data) => {
+                    let stream = tcp_stream
+                        .as_mut()
+                        .ok_or(Error::SendBeforeRecv(PacketProtocol::Tcp))?;
+                    stream.write_all(data)?;
+                }
+                Step::SendUdp(data) => {
+                    let client_addr =
+                        udp_client_addr.ok_or(Error::SendBeforeRecv(PacketProtocol::Udp))?;
+                    let udp_socket = self.udp_socket.as_ref().ok_or_else(no_socket)?;
+                    udp_socket.send_to(data, client_addr)?;
+                }
+                Step::Done => return Ok(()),
+            }
         }
+    }
+
+    fn accept(&self) -> EResult<TcpStream> {
+        let tcp_listener = self.tcp_listener.as_ref().ok_or_else(no_socket)?;
+
+        let stream = if let Some(timeout) = self.timeout {
+            // std has no accept timeout, so poll until the deadline
+            tcp_listener.set_nonblocking(true)?;
+            let deadline = Instant::now() + timeout;
+            loop {
+                match tcp_listener.accept() {
+                    Ok((stream, _address)) => break stream,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        if Instant::now() >= deadline {
+                            return Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+                        }
+                        std::thread::sleep(Duration::from_millis(5));
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        } else {
+            tcp_listener.accept()?.0
+        };
 
-        tcp_stream.set(stream).unwrap();
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(self.timeout)?;
+        Ok(stream)
+    }
+}
 
-        HandleState::Complete
-    };
+/// Next socket operation needed to play back a replay
+pub(crate) enum Step<'a> {
+    /// Read more bytes from the TCP stream, accepting the client's connection if there isn't one
+    ReadTcp,
+    RecvUdp,
+    SendTcp(&'a [u8]),
+    SendUdp(&'a [u8]),
+    Done,
+}
 
-    Ok(state)
+/// Decides what to do for each packet of a replay and compares what the client sent, independent
+/// of the sockets so the threaded and async servers play back replays the same way
+pub(crate) struct Playback<'a> {
+    packets: &'a [Packet],
+    position: usize,
+    /// Bytes received on the TCP stream that haven't been compared yet
+    tcp_pending: Vec<u8>,
+    tcp_ended: bool,
+    mismatches: &'a mut Vec<PacketMismatch>,
 }
 
-fn handle_udp_receive(
-    buf: &mut [u8],
-    udp_client_addr: &OnceCell<SocketAddr>,
-    udp_socket: &UdpSocket,
-    packet: &Packet,
-) -> EResult<HandleState> {
-    let (size, client_addr) = udp_socket.recv_from(buf)?;
+impl<'a> Playback<'a> {
+    pub(crate) fn new(packets: &'a [Packet], mismatches: &'a mut Vec<PacketMismatch>) -> Self {
+        Self {
+            packets,
+            position: 0,
+            tcp_pending: Vec::new(),
+            tcp_ended: false,
+            mismatches,
+        }
+    }
+
+    pub(crate) fn next_step(&mut self) -> Step<'a> {
+        loop {
+            let Some(packet) = self.packets.get(self.position) else {
+                return Step::Done;
+            };
 
-    if udp_client_addr.get().is_none() {
-        udp_client_addr.set(client_addr).unwrap();
+            match (&packet.direction, &packet.protocol) {
+                (PacketDirection::ToServer, PacketProtocol::Tcp) => {
+                    // TCP is a stream so segments may be split or merged differently to the
+                    // capture, compare every consecutive packet from the client at once
+                    let end = tcp_run_end(self.packets, self.position);
+                    let expected = tcp_run(self.packets, self.position, end);
+                    match take_stream(&mut self.tcp_pending, &expected, self.tcp_ended) {
+                        Some(received) => {
+                            self.compare(expected, received);
+                            self.position = end;
+                            self.tcp_ended = false;
+                        }
+                        None => return Step::ReadTcp,
+                    }
+                }
+                (PacketDirection::ToServer, PacketProtocol::Udp) => return Step::RecvUdp,
+                (PacketDirection::FromServer, PacketProtocol::Tcp) => {
+                    self.position += 1;
+                    return Step::SendTcp(&packet.data);
+                }
+                (PacketDirection::FromServer, PacketProtocol::Udp) => {
+                    self.position += 1;
+                    return Step::SendUdp(&packet.data);
+                }
+            }
+        }
     }
 
-    // TODO: Compare data
-    if buf[..size].ne(&packet.data) {
-        println!("Received UDP packet that didn't match");
+    /// Bytes read from the TCP stream after [Step::ReadTcp], empty if the stream ended
+    pub(crate) fn tcp_received(&mut self, data: &[u8]) {
+        self.tcp_ended = data.is_empty();
+        self.tcp_pending.extend_from_slice(data);
     }
 
-    Ok(HandleState::Complete)
-}
+    /// Packet received after [Step::RecvUdp]
+    pub(crate) fn udp_received(&mut self, data: &[u8]) {
+        let expected = self.packets[self.position].data.clone();
+        self.compare(expected, data.to_vec());
+        self.position += 1;
+    }
 
-fn handle_tcp_send(packet: &Packet, tcp_stream: &OnceCell<TcpStream>) -> EResult<HandleState> {
-    if let Some(mut stream) = tcp_stream.get() {
-        let size = stream.write(&packet.data[..])?;
+    fn compare(&mut self, expected: Vec<u8>, received: Vec<u8>) {
+        if received != expected {
+            let protocol = self.packets[self.position].protocol.clone();
+            println!("Received {:?} packet that didn't match", protocol);
+            self.mismatches.push(PacketMismatch {
+                index: self.position,
+                protocol,
+                expected,
+                received,
+            });
+        }
+    }
+}
 
-        Ok(if size < packet.data.len() {
-            HandleState::Incomplete
-        } else {
-            HandleState::Complete
+/// End of the run of TCP packets sent to the server starting at `start`
+fn tcp_run_end(packets: &[Packet], start: usize) -> usize {
+    packets[start..]
+        .iter()
+        .position(|packet| {
+            packet.direction != PacketDirection::ToServer || packet.protocol != PacketProtocol::Tcp
         })
-    } else {
-        Err(Error::SendBeforeRecv(packet.protocol.clone()))
-    }
+        .map_or(packets.len(), |len| start + len)
 }
 
-fn handle_udp_send(
-    packet: &Packet,
-    udp_client_addr: &OnceCell<SocketAddr>,
-    udp_socket: &UdpSocket,
-) -> EResult<HandleState> {
-    if let Some(client_addr) = udp_client_addr.get() {
-        let size = udp_socket.send_to(&packet.data[..], client_addr)?;
+/// Bytes of the packets in a run of TCP packets joined together
+fn tcp_run(packets: &[Packet], start: usize, end: usize) -> Vec<u8> {
+    packets[start..end]
+        .iter()
+        .flat_map(|packet| packet.data.iter().copied())
+        .collect()
+}
 
-        Ok(if size < packet.data.len() {
-            HandleState::Incomplete
-        } else {
-            HandleState::Complete
-        })
+/// Take the bytes received on a TCP stream to compare with `expected`, once enough have been
+/// received, they already differ or the stream has `ended`. Returns [None] if more bytes need to
+/// be read. Any bytes past the expected bytes are left for the next comparison.
+fn take_stream(pending: &mut Vec<u8>, expected: &[u8], ended: bool) -> Option<Vec<u8>> {
+    let len = pending.len().min(expected.len());
+    if pending[..len] != expected[..len] || ended {
+        Some(std::mem::take(pending))
+    } else if pending.len() >= expected.len() {
+        let rest = pending.split_off(expected.len());
+        Some(std::mem::replace(pending, rest))
     } else {
-        Err(Error::SendBeforeRecv(packet.protocol.clone()))
+        None
+    }
+}
+
+fn into_io_error(error: Error) -> std::io::Error {
+    match error {
+        Error::IO(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)),
     }
 }
+
+fn no_socket() -> Error {
+    Error::String("Replay contains a packet for a protocol without a port".to_string())
+}
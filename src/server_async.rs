@@ -0,0 +1,191 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::error::EResult;
+use crate::packet::PacketProtocol;
+use crate::server::{PacketMismatch, Playback, Step};
+use crate::{Error, QueryReplay};
+
+/// Replay server using tokio sockets, only the protocols used in the replay are bound
+pub struct AsyncServer {
+    query_replay: QueryReplay,
+    tcp_listener: Option<TcpListener>,
+    udp_socket: Option<UdpSocket>,
+}
+
+impl AsyncServer {
+    pub async fn bind(address: IpAddr, query_replay: QueryReplay) -> EResult<Self> {
+        let tcp_listener = match query_replay.server.tcp_port {
+            Some(port) => Some(TcpListener::bind(SocketAddr::new(address, port)).await?),
+            None => None,
+        };
+        let udp_socket = match query_replay.server.udp_port {
+            Some(port) => Some(UdpSocket::bind(SocketAddr::new(address, port)).await?),
+            None => None,
+        };
+
+        Ok(Self {
+            query_replay,
+            tcp_listener,
+            udp_socket,
+        })
+    }
+
+    pub async fn run(self) -> EResult<Vec<PacketMismatch>> {
+        // Received packets can be larger than any that were captured
+        let mut buf = vec![0u8; self.query_replay.server.packet_size.max(u16::MAX as usize)];
+        let mut udp_client_addr = None;
+        let mut tcp_stream: Option<TcpStream> = None;
+        let mut mismatches = Vec::new();
+        let mut playback = Playback::new(&self.query_replay.packets, &mut mismatches);
+
+        loop {
+            match playback.next_step() {
+                Step::ReadTcp => {
+                    let stream = match &mut tcp_stream {
+                        Some(stream) => stream,
+                        None => {
+                            let listener = self.tcp_listener.as_ref().ok_or_else(no_socket)?;
+                            let (stream, _address) = listener.accept().await?;
+                            tcp_stream.insert(stream)
+                        }
+                    };
+                    let size = stream.read(&mut buf).await?;
+                    playback.tcp_received(&buf[..size]);
+                }
+                Step::RecvUdp => {
+                    let socket = self.udp_socket.as_ref().ok_or_else(no_socket)?;
+                    let (size, client_addr) = socket.recv_from(&mut buf).await?;
+                    udp_client_addr.get_or_insert(client_addr);
+                    playback.udp_received(&buf[..size]);
+                }
+                Step::SendTcp(data) => {
+                    let stream = tcp_stream
+                        .as_mut()
+                        .ok_or(Error::SendBeforeRecv(PacketProtocol::Tcp))?;
+                    stream.write_all(data).await?;
+                }
+                Step::SendUdp(data) => {
+                    let client_addr =
+                        udp_client_addr.ok_or(Error::SendBeforeRecv(PacketProtocol::Udp))?;
+                    let socket = self.udp_socket.as_ref().ok_or_else(no_socket)?;
+                    socket.send_to(data, client_addr).await?;
+                }
+                Step::Done => break,
+            }
+        }
+
+        Ok(mismatches)
+    }
+}
+
+fn no_socket() -> Error {
+    Error::String("Replay contains a packet for a protocol without a port".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::implementations::{AsyncQueryImplementation, BoxFuture};
+    use crate::options::ServerOptions;
+    use crate::packet::{Packet, PacketDirection, PacketProtocol};
+    use crate::value::CommonValue;
+    use crate::{error::GenericError, replay_async, QueryOptions, QueryReplay, REPLAY_VERSION};
+
+    /// Sends a ping and uses the response as the server name
+    struct PingImpl;
+
+    impl AsyncQueryImplementation for PingImpl {
+        fn query_server<'a>(
+            &'a self,
+            options: &'a QueryOptions,
+        ) -> BoxFuture<'a, Result<CommonValue, GenericError>> {
+            Box::pin(async move {
+                let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+                socket
+                    .send_to(b"ping", (options.address.as_str(), options.port.unwrap()))
+                    .await?;
+                let mut buf = [0; 16];
+                let size = socket.recv(&mut buf).await?;
+
+                Ok(CommonValue {
+                    name: Some(String::from_utf8_lossy(&buf[..size]).into_owned()),
+                    map: None,
+                    has_password: None,
+                    players_online: None,
+                    players_maximum: None,
+                    player_names: HashSet::new(),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_udp() {
+        let packet = |direction, data: &[u8]| Packet {
+            direction,
+            protocol: PacketProtocol::Udp,
+            src_port: 27015,
+            dst_port: 27015,
+            data: data.to_vec(),
+        };
+
+        let replay = QueryReplay {
+            query: QueryOptions {
+                address: "127.0.0.1".to_string(),
+                port: Some(27015),
+                game: "ping".to_string(),
+                request: Default::default(),
+            },
+            server: ServerOptions {
+                tcp_port: None,
+                udp_port: Some(27015),
+                packet_size: 4,
+            },
+            packets: vec![
+                packet(PacketDirection::ToServer, b"ping"),
+                packet(PacketDirection::FromServer, b"pong"),
+            ],
+            value: CommonValue {
+                name: Some("pong".to_string()),
+                map: None,
+                has_password: None,
+                players_online: None,
+                players_maximum: None,
+                player_names: HashSet::new(),
+            },
+            replay_version: REPLAY_VERSION,
+        };
+
+        let outcome = replay_async(&PingImpl, replay).await.unwrap();
+        assert!(outcome.matches());
+    }
+
+    /// Never finishes, like an implementation without a timeout waiting for a response
+    struct PendingImpl;
+
+    impl AsyncQueryImplementation for PendingImpl {
+        fn query_server<'a>(
+            &'a self,
+            _options: &'a QueryOptions,
+        ) -> BoxFuture<'a, Result<CommonValue, GenericError>> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_server_error() {
+        // Nothing to respond to, so the server fails immediately
+        let replay = crate::mock::MockServer::udp()
+            .respond(b"pong".to_vec())
+            .into_replay();
+
+        assert!(matches!(
+            replay_async(&PendingImpl, replay).await,
+            Err(crate::Error::SendBeforeRecv(PacketProtocol::Udp))
+        ));
+    }
+}
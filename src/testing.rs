@@ -0,0 +1,316 @@
+//! Helpers for using replays as fixtures in cargo tests
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::implementations::QueryImplementation;
+use crate::options::{find_replays, RequestSettings};
+use crate::report::hex;
+use crate::server::PacketMismatch;
+use crate::value::FieldDifference;
+use crate::{run_replay, QueryOptions, QueryReplay};
+
+/// Replay a fixture file with an implementation, panicking with the value differences, mismatched
+/// packets and unplayed packets if it doesn't match the capture
+#[track_caller]
+pub fn assert_replay(implementation: &dyn QueryImplementation, path: impl AsRef<Path>) {
+    let path = path.as_ref();
+
+    let query_replay = QueryReplay::load(path)
+        .unwrap_or_else(|e| panic!("Unable to load replay {}: {:?}", path.display(), e));
+
+    let outcome = run_replay(implementation, query_replay)
+        .unwrap_or_else(|e| panic!("Replay {} failed: {:?}", path.display(), e));
+
+    if !outcome.matches() || !outcome.packets_match() {
+        let mut message = format!("Replay {} did not match:", path.display());
+        for diff in outcome.expected.differences(&outcome.value) {
+            message.push_str(&format!(
+                "\n  \"{}\" => expected({}) value({})",
+                diff.field, diff.expected, diff.value
+            ));
+        }
+        for mismatch in &outcome.packet_mismatches {
+            message.push_str(&format!(
+                "\n  packet {} ({:?}) expected {} received {}",
+                mismatch.index,
+                mismatch.protocol,
+                hex(&mismatch.expected),
+                hex(&mismatch.received)
+            ));
+        }
+        if !outcome.complete {
+            message.push_str("\n  not all packets were played back");
+        }
+        panic!("{}", message);
+    }
+}
+
+/// Result of re-blessing a replay file
+#[derive(Debug, Clone)]
+pub enum BlessOutcome {
+    /// The implementation already produced the stored value
+    Unchanged,
+    /// The stored value was replaced, contains the fields that changed
+    Updated(Vec<FieldDifference>),
+    /// Packets didn't match the capture so the file was left unchanged, contains the mismatched
+    /// packets (empty if the implementation didn't send every packet)
+    Refused(Vec<PacketMismatch>),
+}
+
+/// Replay a file and if packets match but the produced value differs write the new value back to
+/// the file. Used when an implementation intentionally changes its output. The overrides are only
+/// used for the replay, the file keeps its stored request settings.
+pub fn bless(
+    implementation: &dyn QueryImplementation,
+    path: impl AsRef<Path>,
+    overrides: &RequestSettings,
+) -> Result<BlessOutcome, crate::Error> {
+    let path = path.as_ref();
+    let mut query_replay = QueryReplay::load(path)?;
+
+    let mut replayed = query_replay.clone();
+    replayed.query.request.merge(overrides);
+    let outcome = run_replay(implementation, replayed)?;
+
+    if !outcome.packets_match() {
+        return Ok(BlessOutcome::Refused(outcome.packet_mismatches));
+    }
+
+    if outcome.matches() {
+        return Ok(BlessOutcome::Unchanged);
+    }
+
+    let differences = outcome.expected.differences(&outcome.value);
+    query_replay.value = outcome.value;
+    query_replay.save(path)?;
+
+    Ok(BlessOutcome::Updated(differences))
+}
+
+/// Environment variable used to choose the [RecordMode]
+pub const RECORD_MODE_ENV: &str = "NET_REPLAY";
+/// Environment variable used to choose the network device to record on
+pub const RECORD_DEVICE_ENV: &str = "NET_REPLAY_DEVICE";
+/// Environment variable that censors player names in recordings when set to "1" or "true"
+pub const RECORD_CENSOR_ENV: &str = "NET_REPLAY_CENSOR";
+
+/// Environment variable with the seed for censored player names, a number or any string
+pub const RECORD_SEED_ENV: &str = "NET_REPLAY_SEED";
+/// Environment variable that records even if censored player names are still found, when set to
+/// "1" or "true"
+pub const RECORD_ALLOW_LEAKS_ENV: &str = "NET_REPLAY_ALLOW_LEAKS";
+
+/// Whether fixtures are replayed or recorded by [assert_replay_or_record]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordMode {
+    /// Only replay, missing fixtures fail (`NET_REPLAY=replay`)
+    Replay,
+    /// Always record a new fixture (`NET_REPLAY=record`)
+    Record,
+    /// Replay if the fixture exists otherwise record it (default)
+    Auto,
+}
+
+impl RecordMode {
+    pub fn from_env() -> Self {
+        match std::env::var(RECORD_MODE_ENV).as_deref() {
+            Ok("replay") => RecordMode::Replay,
+            Ok("record") => RecordMode::Record,
+            _ => RecordMode::Auto,
+        }
+    }
+}
+
+/// Replay a fixture file, or if it is missing (or recording is forced with `NET_REPLAY=record`)
+/// capture a live query with the given options and save it as the fixture. Recording requires
+/// the capture feature and capture privileges.
+#[track_caller]
+pub fn assert_replay_or_record(
+    implementation: &dyn QueryImplementation,
+    path: impl AsRef<Path>,
+    options: QueryOptions,
+) {
+    let path = path.as_ref();
+    let mode = RecordMode::from_env();
+
+    let should_record = match mode {
+        RecordMode::Replay => false,
+        RecordMode::Record => true,
+        RecordMode::Auto => !path.exists(),
+    };
+
+    if !should_record {
+        return assert_replay(implementation, path);
+    }
+
+    record(implementation, path, options)
+        .unwrap_or_else(|e| panic!("Unable to record replay {}: {:?}", path.display(), e));
+    println!("Recorded {}", path.display());
+}
+
+#[cfg(feature = "capture")]
+fn record(
+    implementation: &dyn QueryImplementation,
+    path: &Path,
+    options: QueryOptions,
+) -> Result<(), crate::Error> {
+    let device = std::env::var(RECORD_DEVICE_ENV).ok();
+    let is_set = |name| matches!(std::env::var(name).as_deref(), Ok("1") | Ok("true"));
+    let censor_player_names = is_set(RECORD_CENSOR_ENV);
+    let pseudonym_seed = std::env::var(RECORD_SEED_ENV)
+        .map(|seed| crate::packet_filter::parse_seed(&seed))
+        .unwrap_or(crate::packet_filter::DEFAULT_PSEUDONYM_SEED);
+
+    let query_replay = crate::run_capture(
+        implementation,
+        options,
+        device.as_deref(),
+        None::<&Path>,
+        censor_player_names,
+        pseudonym_seed,
+        is_set(RECORD_ALLOW_LEAKS_ENV),
+    )?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    query_replay.save(path)
+}
+
+#[cfg(not(feature = "capture"))]
+fn record(
+    _implementation: &dyn QueryImplementation,
+    _path: &Path,
+    _options: QueryOptions,
+) -> Result<(), crate::Error> {
+    Err(crate::Error::String(
+        "Recording replays requires the capture feature".to_string(),
+    ))
+}
+
+/// Assert that replaying a fixture file with an implementation produces the stored value. With
+/// `record: options` the fixture is recorded if missing, see [assert_replay_or_record].
+///
+/// ```no_run
+/// # use net_replay_test::assert_replay;
+/// # use net_replay_test::implementations::RustImpl;
+/// # use net_replay_test::QueryOptions;
+/// assert_replay!(RustImpl::default(), "tests/replays/csgo.json");
+///
+/// let options = QueryOptions {
+///     address: "127.0.0.1".to_string(),
+///     port: Some(27015),
+///     game: "csgo".to_string(),
+///     request: Default::default(),
+/// };
+/// assert_replay!(RustImpl::default(), "tests/replays/csgo.json", record: options);
+/// ```
+#[macro_export]
+macro_rules! assert_replay {
+    ($implementation: expr, $path: expr) => {
+        $crate::testing::assert_replay(&$implementation, $path)
+    };
+    ($implementation: expr, $path: expr, record: $options: expr) => {
+        $crate::testing::assert_replay_or_record(&$implementation, $path, $options)
+    };
+}
+
+/// Generate a `#[test]` function for every replay in a directory, intended to be called from a
+/// build script. Tests are written to `out_file` in `OUT_DIR` and can be included in a test with
+/// `include!(concat!(env!("OUT_DIR"), "/<out_file>"))`. `implementation` is a rust expression that
+/// creates the implementation to test with.
+///
+/// ```no_run
+/// // build.rs
+/// net_replay_test::testing::generate_replay_tests(
+///     "tests/replays",
+///     "net_replay_test::implementations::RustImpl::default()",
+///     "replay_tests.rs",
+/// )
+/// .unwrap();
+/// ```
+pub fn generate_replay_tests(
+    replay_dir: impl AsRef<Path>,
+    implementation: &str,
+    out_file: &str,
+) -> std::io::Result<()> {
+    let replay_dir = replay_dir.as_ref();
+    println!("cargo:rerun-if-changed={}", replay_dir.display());
+
+    let out_dir = std::env::var_os("OUT_DIR")
+        .map(PathBuf::from)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "OUT_DIR is not set"))?;
+    let mut out = std::fs::File::create(out_dir.join(out_file))?;
+
+    let mut names = HashSet::new();
+    for replay in find_replays(replay_dir)? {
+        let replay = std::fs::canonicalize(replay)?;
+        let name = test_name(&replay, &mut names);
+
+        writeln!(
+            out,
+            "#[test]\nfn {}() {{\n    net_replay_test::assert_replay!({}, {:?});\n}}\n",
+            name, implementation, replay
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Create a unique test function name from a replay's file name
+fn test_name(path: &Path, used: &mut HashSet<String>) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    // Captures from the CLI are already prefixed
+    let stem = stem.strip_prefix("replay-").unwrap_or(&stem);
+
+    let mut name = String::from("replay_");
+    for c in stem.chars() {
+        if c.is_ascii_alphanumeric() {
+            name.push(c.to_ascii_lowercase());
+        } else if !name.ends_with('_') {
+            name.push('_');
+        }
+    }
+    let name = name.trim_end_matches('_').to_string();
+
+    let mut unique = name.clone();
+    let mut i = 1;
+    while !used.insert(unique.clone()) {
+        i += 1;
+        unique = format!("{}_{}", name, i);
+    }
+    unique
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use super::test_name;
+
+    #[test]
+    fn replay_test_names() {
+        let mut used = HashSet::new();
+        assert_eq!(
+            test_name(
+                Path::new("replay-2023-10-09T12:00:00Z-csgo-127.0.0.1.json"),
+                &mut used
+            ),
+            "replay_2023_10_09t12_00_00z_csgo_127_0_0_1"
+        );
+        assert_eq!(
+            test_name(Path::new("a/csgo.json"), &mut used),
+            "replay_csgo"
+        );
+        assert_eq!(
+            test_name(Path::new("b/csgo.json"), &mut used),
+            "replay_csgo_2"
+        );
+    }
+}
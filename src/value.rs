@@ -14,33 +14,72 @@ pub struct CommonValue {
     pub player_names: HashSet<String>,
 }
 
-macro_rules! print_diff {
-    ($name: expr, $self: expr, $other: expr) => {
+/// A single field that differs between two [CommonValue]s
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct FieldDifference {
+    pub field: String,
+    pub expected: String,
+    pub value: String,
+}
+
+macro_rules! push_diff {
+    ($diffs: expr, $name: expr, $self: expr, $other: expr) => {
         if $self != $other {
-            println!(
-                "  \"{}\" => expected({:?}) value({:?})",
-                $name, $self, $other
-            );
+            $diffs.push(FieldDifference {
+                field: $name.to_string(),
+                expected: format!("{:?}", $self),
+                value: format!("{:?}", $other),
+            });
         }
     };
 }
 
 impl CommonValue {
-    pub fn print_difference(&self, other: &CommonValue) {
-        println!("CommonValue diff {{");
+    /// List the fields that differ between this (expected) value and another value, player names
+    /// are compared individually
+    pub fn differences(&self, other: &CommonValue) -> Vec<FieldDifference> {
+        let mut diffs = Vec::new();
 
-        print_diff!("name", self.name, other.name);
-        print_diff!("map", self.map, other.map);
-        print_diff!("has_password", self.has_password, other.has_password);
-        print_diff!("players_online", self.players_online, other.players_online);
-        print_diff!(
+        push_diff!(diffs, "name", self.name, other.name);
+        push_diff!(diffs, "map", self.map, other.map);
+        push_diff!(diffs, "has_password", self.has_password, other.has_password);
+        push_diff!(
+            diffs,
+            "players_online",
+            self.players_online,
+            other.players_online
+        );
+        push_diff!(
+            diffs,
             "players_maximum",
             self.players_maximum,
             other.players_maximum
         );
 
-        for diff in self.player_names.difference(&other.player_names) {
-            println!("  {:?}", diff);
+        let mut missing: Vec<_> = self.player_names.difference(&other.player_names).collect();
+        missing.sort();
+        for name in missing {
+            push_diff!(diffs, "player_names", Some(name), None::<&String>);
+        }
+
+        let mut extra: Vec<_> = other.player_names.difference(&self.player_names).collect();
+        extra.sort();
+        for name in extra {
+            push_diff!(diffs, "player_names", None::<&String>, Some(name));
+        }
+
+        diffs
+    }
+
+    pub fn print_difference(&self, other: &CommonValue) {
+        println!("CommonValue diff {{");
+
+        for diff in self.differences(other) {
+            println!(
+                "  \"{}\" => expected({}) value({})",
+                diff.field, diff.expected, diff.value
+            );
         }
 
         println!("}}");
@@ -119,3 +158,123 @@ impl TryFrom<serde_json::Value> for CommonValue {
         })
     }
 }
+
+/// JSON pointers describing where each [CommonValue] field can be found in the JSON output of an
+/// external implementation, defaults match the output of node-gamedig
+#[cfg(feature = "impl_command")]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FieldMapping {
+    /// If this pointer is present in the output the query is treated as failed
+    pub error: Option<String>,
+    pub name: Option<String>,
+    pub map: Option<String>,
+    pub has_password: Option<String>,
+    /// When unset the length of the players array is used
+    pub players_online: Option<String>,
+    pub players_maximum: Option<String>,
+    /// Pointer to an array of players
+    pub players: Option<String>,
+    /// Pointer to the name of a player, relative to each element of the players array
+    pub player_name: Option<String>,
+}
+
+#[cfg(feature = "impl_command")]
+impl Default for FieldMapping {
+    fn default() -> Self {
+        Self {
+            error: Some("/error".to_string()),
+            name: Some("/name".to_string()),
+            map: Some("/map".to_string()),
+            has_password: Some("/password".to_string()),
+            players_online: None,
+            players_maximum: Some("/maxplayers".to_string()),
+            players: Some("/players".to_string()),
+            player_name: Some("/name".to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "impl_command")]
+impl FieldMapping {
+    /// Set the pointer for a field by name, an empty pointer unsets the field
+    pub fn set(&mut self, field: &str, pointer: &str) -> Result<(), Error> {
+        let pointer = if pointer.is_empty() {
+            None
+        } else {
+            Some(pointer.to_string())
+        };
+
+        match field {
+            "error" => self.error = pointer,
+            "name" => self.name = pointer,
+            "map" => self.map = pointer,
+            "has_password" => self.has_password = pointer,
+            "players_online" => self.players_online = pointer,
+            "players_maximum" => self.players_maximum = pointer,
+            "players" => self.players = pointer,
+            "player_name" => self.player_name = pointer,
+            _ => return Err(Error::String(format!("Unknown mapping field {:?}", field))),
+        }
+
+        Ok(())
+    }
+
+    /// Convert JSON output into a [CommonValue] using this mapping
+    pub fn apply(&self, value: &serde_json::Value) -> Result<CommonValue, Error> {
+        let get = |pointer: &Option<String>| {
+            pointer
+                .as_ref()
+                .and_then(|pointer| value.pointer(pointer))
+                .filter(|v| !v.is_null())
+        };
+
+        if let Some(error) = get(&self.error) {
+            return Err(Error::String(error.to_string()));
+        }
+
+        let players = get(&self.players).and_then(|v| v.as_array());
+
+        Ok(CommonValue {
+            name: get(&self.name).and_then(json_to_string),
+            map: get(&self.map).and_then(json_to_string),
+            has_password: get(&self.has_password).and_then(|v| v.as_bool()),
+            players_online: get(&self.players_online)
+                .and_then(json_to_u64)
+                .or_else(|| players.map(|v| v.len().try_into().expect("usize should fit in u64"))),
+            players_maximum: get(&self.players_maximum).and_then(json_to_u64),
+            player_names: players
+                .map(|players| {
+                    players
+                        .iter()
+                        .filter_map(|player| {
+                            self.player_name
+                                .as_ref()
+                                .and_then(|pointer| player.pointer(pointer))
+                                .and_then(json_to_string)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(feature = "impl_command")]
+fn json_to_string(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "impl_command")]
+fn json_to_u64(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}